@@ -0,0 +1,9 @@
+USE newsman;
+
+CREATE TABLE IF NOT EXISTS list_members (
+    token varchar(255) NOT NULL,
+    list varchar(255) NOT NULL,
+    PRIMARY KEY (token, list)
+);
+
+ALTER TABLE jobs ADD COLUMN list varchar(255);
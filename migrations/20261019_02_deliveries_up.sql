@@ -0,0 +1,14 @@
+USE newsman;
+
+ALTER TABLE jobs ADD COLUMN id BIGINT NOT NULL AUTO_INCREMENT PRIMARY KEY FIRST;
+ALTER TABLE jobs ADD COLUMN dry_run BOOLEAN NOT NULL DEFAULT FALSE;
+
+CREATE TABLE IF NOT EXISTS deliveries (
+    job BIGINT NOT NULL,
+    newsletter varchar(255) NOT NULL,
+    token varchar(255) NOT NULL,
+    email varchar(255) NOT NULL,
+    status varchar(255) NOT NULL,
+    time BIGINT NOT NULL,
+    error TEXT
+);
@@ -0,0 +1,15 @@
+USE newsman;
+
+CREATE TABLE IF NOT EXISTS suppressions (
+    hash varchar(255) NOT NULL PRIMARY KEY,
+    email varchar(255),
+    reason varchar(255) NOT NULL,
+    time BIGINT NOT NULL
+);
+
+-- emails are stored trimmed with a lower case domain, duplicates and suppressions are
+-- checked against the whole email in lower case
+UPDATE mailing_list SET email = TRIM(email);
+UPDATE mailing_list SET email = CONCAT(SUBSTRING(email, 1, LENGTH(email) - LENGTH(SUBSTRING_INDEX(email, '@', -1))),
+                                       LOWER(SUBSTRING_INDEX(email, '@', -1)))
+    WHERE email LIKE '%@%';
@@ -0,0 +1,12 @@
+USE newsman;
+
+CREATE TABLE IF NOT EXISTS bounces (
+    email varchar(255) NOT NULL,
+    job BIGINT,
+    kind varchar(255) NOT NULL,
+    status varchar(255) NOT NULL,
+    time BIGINT NOT NULL
+);
+
+ALTER TABLE bounces ADD COLUMN message_id varchar(255);
+ALTER TABLE bounces ADD UNIQUE KEY (message_id, email);
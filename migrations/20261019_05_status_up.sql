@@ -0,0 +1,3 @@
+USE newsman;
+
+ALTER TABLE mailing_list ADD COLUMN status varchar(255) NOT NULL DEFAULT 'subscribed';
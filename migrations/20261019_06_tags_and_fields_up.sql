@@ -0,0 +1,16 @@
+USE newsman;
+
+ALTER TABLE mailing_list ADD COLUMN name varchar(255);
+
+CREATE TABLE IF NOT EXISTS subscriber_tags (
+    token varchar(255) NOT NULL,
+    tag varchar(255) NOT NULL,
+    PRIMARY KEY (token, tag)
+);
+
+CREATE TABLE IF NOT EXISTS subscriber_fields (
+    token varchar(255) NOT NULL,
+    name varchar(255) NOT NULL,
+    value TEXT NOT NULL,
+    PRIMARY KEY (token, name)
+);
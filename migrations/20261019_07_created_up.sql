@@ -0,0 +1,3 @@
+USE newsman;
+
+ALTER TABLE mailing_list ADD COLUMN created BIGINT NOT NULL DEFAULT 0;
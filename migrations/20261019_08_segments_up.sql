@@ -0,0 +1,9 @@
+USE newsman;
+
+ALTER TABLE jobs ADD COLUMN segment TEXT;
+
+CREATE TABLE IF NOT EXISTS opens (
+    token varchar(255) NOT NULL,
+    job BIGINT NOT NULL,
+    time BIGINT NOT NULL
+);
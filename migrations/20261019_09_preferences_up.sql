@@ -0,0 +1,9 @@
+USE newsman;
+
+ALTER TABLE mailing_list ADD COLUMN locale varchar(255);
+ALTER TABLE mailing_list ADD COLUMN frequency varchar(255) NOT NULL DEFAULT 'all';
+
+CREATE TABLE IF NOT EXISTS unsubscribe_feedback (
+    reason TEXT NOT NULL,
+    time BIGINT NOT NULL
+);
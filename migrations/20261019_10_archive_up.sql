@@ -0,0 +1,13 @@
+USE newsman;
+
+ALTER TABLE jobs ADD COLUMN archive BOOLEAN NOT NULL DEFAULT FALSE;
+
+CREATE TABLE IF NOT EXISTS issues (
+    id BIGINT NOT NULL AUTO_INCREMENT PRIMARY KEY,
+    job BIGINT NOT NULL,
+    newsletter varchar(255) NOT NULL,
+    subject varchar(255) NOT NULL,
+    preheader TEXT,
+    body LONGTEXT NOT NULL,
+    sent BIGINT NOT NULL
+);
@@ -0,0 +1,3 @@
+USE newsman;
+
+ALTER TABLE suppressions ADD COLUMN notified BIGINT;
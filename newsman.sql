@@ -5,11 +5,89 @@ USE newsman;
 
 CREATE TABLE IF NOT EXISTS mailing_list (
     token varchar(255) NOT NULL PRIMARY KEY,
-    email varchar(255) NOT NULL
+    email varchar(255) NOT NULL,
+    name varchar(255),
+    status varchar(255) NOT NULL DEFAULT 'subscribed',
+    created BIGINT NOT NULL DEFAULT 0,
+    locale varchar(255),
+    frequency varchar(255) NOT NULL DEFAULT 'all'
+);
+
+CREATE TABLE IF NOT EXISTS suppressions (
+    hash varchar(255) NOT NULL PRIMARY KEY,
+    email varchar(255),
+    reason varchar(255) NOT NULL,
+    time BIGINT NOT NULL,
+    notified BIGINT
+);
+
+CREATE TABLE IF NOT EXISTS list_members (
+    token varchar(255) NOT NULL,
+    list varchar(255) NOT NULL,
+    PRIMARY KEY (token, list)
+);
+
+CREATE TABLE IF NOT EXISTS subscriber_tags (
+    token varchar(255) NOT NULL,
+    tag varchar(255) NOT NULL,
+    PRIMARY KEY (token, tag)
+);
+
+CREATE TABLE IF NOT EXISTS subscriber_fields (
+    token varchar(255) NOT NULL,
+    name varchar(255) NOT NULL,
+    value TEXT NOT NULL,
+    PRIMARY KEY (token, name)
 );
 
 CREATE TABLE IF NOT EXISTS jobs (
+    id BIGINT NOT NULL AUTO_INCREMENT PRIMARY KEY,
     newsletter varchar(255) NOT NULL,
     time BIGINT NOT NULL,
-    subject varchar(255) NOT NULL
+    subject varchar(255) NOT NULL,
+    list varchar(255),
+    dry_run BOOLEAN NOT NULL DEFAULT FALSE,
+    segment TEXT,
+    archive BOOLEAN NOT NULL DEFAULT FALSE
+);
+
+CREATE TABLE IF NOT EXISTS deliveries (
+    job BIGINT NOT NULL,
+    newsletter varchar(255) NOT NULL,
+    token varchar(255) NOT NULL,
+    email varchar(255) NOT NULL,
+    status varchar(255) NOT NULL,
+    time BIGINT NOT NULL,
+    error TEXT
+);
+
+CREATE TABLE IF NOT EXISTS bounces (
+    email varchar(255) NOT NULL,
+    job BIGINT,
+    kind varchar(255) NOT NULL,
+    status varchar(255) NOT NULL,
+    time BIGINT NOT NULL,
+    message_id varchar(255),
+    UNIQUE KEY (message_id, email)
+);
+
+CREATE TABLE IF NOT EXISTS opens (
+    token varchar(255) NOT NULL,
+    job BIGINT NOT NULL,
+    time BIGINT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS unsubscribe_feedback (
+    reason TEXT NOT NULL,
+    time BIGINT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS issues (
+    id BIGINT NOT NULL AUTO_INCREMENT PRIMARY KEY,
+    job BIGINT NOT NULL,
+    newsletter varchar(255) NOT NULL,
+    subject varchar(255) NOT NULL,
+    preheader TEXT,
+    body LONGTEXT NOT NULL,
+    sent BIGINT NOT NULL
 );
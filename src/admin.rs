@@ -0,0 +1,484 @@
+use std::convert::Infallible;
+use serde::{Deserialize, Serialize};
+use sqlx::mysql::{MySqlPool, MySqlPoolOptions};
+use warp::{Filter, body::content_length_limit, http::StatusCode};
+use chrono::Utc;
+use log::debug;
+
+use crate::Config;
+use crate::emails::{self, add_subscriber, normalise_email, update_subscriber, AddError, NewSubscriber, SubscriberChanges};
+use crate::job;
+use crate::newsletter::parse_time;
+use crate::preferences::load_with_token;
+use crate::server::{bearer_authorised, json_reply, signup_result};
+use crate::subscribers::{self, load_subscriber, Subscriber};
+
+type Reply = warp::reply::WithStatus<warp::reply::Json>;
+
+fn data_reply<T: Serialize>(status: StatusCode, data: &T) -> Reply {
+    warp::reply::with_status(warp::reply::json(data), status)
+}
+
+/// Loads the config when the request has a bearer token from its `api_tokens`.
+fn authorise(authorization: Option<String>) -> Result<Config, Reply> {
+    let config: Config = Config::load_config().unwrap();
+    if bearer_authorised(authorization.as_deref(), &config.api_tokens) {
+        Ok(config)
+    } else {
+        Err(json_reply(StatusCode::UNAUTHORIZED, String::from("Invalid API token")))
+    }
+}
+
+async fn connect(config: &Config) -> MySqlPool {
+    MySqlPoolOptions::new()
+        .max_connections(5)
+        .connect(&config.url)
+        .await
+        .expect("Cannot connect to database!")
+}
+
+/// Path segments aren't decoded by warp, so emails and newsletter names with spaces or
+/// other escaped characters are decoded here.
+fn decode(segment: &str) -> String {
+    let bytes: &[u8] = segment.as_bytes();
+    let mut decoded: Vec<u8> = Vec::new();
+    let mut index: usize = 0;
+    while index < bytes.len() {
+        let escaped: Option<u8> = match bytes[index] {
+            b'%' => segment.get(index + 1..index + 3).and_then(|hex| u8::from_str_radix(hex, 16).ok()),
+            _ => None,
+        };
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                index += 3;
+            },
+            None => {
+                decoded.push(bytes[index]);
+                index += 1;
+            },
+        }
+    }
+    String::from_utf8_lossy(&decoded).to_string()
+}
+
+/// Finds the subscriber an email in the path refers to, replying with 400 or 404 when
+/// there isn't one.
+async fn find_subscriber(pool: &MySqlPool, email: &str) -> Result<Subscriber, Reply> {
+    let email: String = match normalise_email(&decode(email)) {
+        Ok(email) => email,
+        Err(err) => return Err(json_reply(StatusCode::BAD_REQUEST, err)),
+    };
+    match load_subscriber(pool, &email).await {
+        Ok(Some(subscriber)) => Ok(subscriber),
+        Ok(None) => Err(json_reply(StatusCode::NOT_FOUND, format!("The email {} doesn't exist in the database", email))),
+        Err(err) => Err(json_reply(StatusCode::INTERNAL_SERVER_ERROR, err)),
+    }
+}
+
+/// Filters for listing subscribers, the same as --subscribers, with tags comma separated.
+#[derive(Deserialize, Clone)]
+pub struct SubscriberQuery {
+    pub list: Option<String>,
+    pub status: Option<String>,
+    pub tag: Option<String>,
+    pub since: Option<String>,
+}
+
+pub async fn handle_list_subscribers(authorization: Option<String>, query: SubscriberQuery) -> Result<impl warp::Reply, Infallible> {
+    debug!("handling subscriber list request...");
+    if let Err(reply) = authorise(authorization) {
+        return Ok(reply);
+    }
+    let tags: Vec<String> = query.tag.iter()
+        .flat_map(|tags| tags.split(','))
+        .map(|tag| tag.trim().to_string())
+        .filter(|tag| !tag.is_empty())
+        .collect();
+    let filter = match subscribers::Filter::new(query.list, query.status, tags, query.since) {
+        Ok(filter) => filter,
+        Err(err) => return Ok(json_reply(StatusCode::BAD_REQUEST, err)),
+    };
+
+    match subscribers::filtered(&filter).await {
+        Ok(subscribers) => Ok(data_reply(StatusCode::OK, &subscribers)),
+        Err(err) => Ok(json_reply(StatusCode::INTERNAL_SERVER_ERROR, err)),
+    }
+}
+
+/// Adds a subscriber the same as -a, any custom field can be set.
+pub async fn handle_add_subscriber(authorization: Option<String>, subscriber: NewSubscriber) -> Result<impl warp::Reply, Infallible> {
+    debug!("handling subscriber add request...");
+    let config: Config = match authorise(authorization) {
+        Ok(config) => config,
+        Err(reply) => return Ok(reply),
+    };
+    let pool: MySqlPool = connect(&config).await;
+
+    let result = add_subscriber(&pool, &config, &subscriber, true).await;
+    let (status, _, message) = match result {
+        // the existing subscriber was given the new lists, tags and fields
+        Ok(emails::Added::Existing(_)) => (StatusCode::OK, "updated", String::new()),
+        _ => signup_result(&result),
+    };
+    match result {
+        Ok(emails::Added::New(token)) | Ok(emails::Added::Existing(token)) => match load_with_token(&pool, &token).await {
+            Ok(Some(subscriber)) => Ok(data_reply(status, &subscriber)),
+            Ok(None) => Ok(json_reply(StatusCode::NOT_FOUND, format!("Subscriber {} was removed", token))),
+            Err(err) => Ok(json_reply(StatusCode::INTERNAL_SERVER_ERROR, err)),
+        },
+        // only the owner of the email can take back an unsubscribe, by signing up again
+        Err(AddError::Unsubscribed(err)) => Ok(json_reply(StatusCode::CONFLICT, err)),
+        Err(_) => Ok(json_reply(status, message)),
+    }
+}
+
+pub async fn handle_get_subscriber(email: String, authorization: Option<String>) -> Result<impl warp::Reply, Infallible> {
+    debug!("handling subscriber request...");
+    let config: Config = match authorise(authorization) {
+        Ok(config) => config,
+        Err(reply) => return Ok(reply),
+    };
+    let pool: MySqlPool = connect(&config).await;
+
+    match find_subscriber(&pool, &email).await {
+        Ok(subscriber) => Ok(data_reply(StatusCode::OK, &subscriber)),
+        Err(reply) => Ok(reply),
+    }
+}
+
+pub async fn handle_update_subscriber(email: String, authorization: Option<String>, changes: SubscriberChanges) -> Result<impl warp::Reply, Infallible> {
+    debug!("handling subscriber update request...");
+    let config: Config = match authorise(authorization) {
+        Ok(config) => config,
+        Err(reply) => return Ok(reply),
+    };
+    let pool: MySqlPool = connect(&config).await;
+    let subscriber: Subscriber = match find_subscriber(&pool, &email).await {
+        Ok(subscriber) => subscriber,
+        Err(reply) => return Ok(reply),
+    };
+
+    match update_subscriber(&pool, &config, &subscriber, &changes).await {
+        Ok(_) => (),
+        Err(AddError::Invalid(err)) | Err(AddError::Suppressed(err)) | Err(AddError::Unsubscribed(err)) => return Ok(json_reply(StatusCode::BAD_REQUEST, err)),
+        Err(AddError::Database(err)) => return Ok(json_reply(StatusCode::INTERNAL_SERVER_ERROR, err)),
+    }
+    match load_subscriber(&pool, &subscriber.email).await {
+        Ok(Some(subscriber)) => Ok(data_reply(StatusCode::OK, &subscriber)),
+        Ok(None) => Ok(json_reply(StatusCode::NOT_FOUND, format!("The email {} doesn't exist in the database", subscriber.email))),
+        Err(err) => Ok(json_reply(StatusCode::INTERNAL_SERVER_ERROR, err)),
+    }
+}
+
+/// Removes a subscriber the same as -r, adding them to the suppression list.
+pub async fn handle_remove_subscriber(email: String, authorization: Option<String>) -> Result<impl warp::Reply, Infallible> {
+    debug!("handling subscriber remove request...");
+    let config: Config = match authorise(authorization) {
+        Ok(config) => config,
+        Err(reply) => return Ok(reply),
+    };
+    let pool: MySqlPool = connect(&config).await;
+    let subscriber: Subscriber = match find_subscriber(&pool, &email).await {
+        Ok(subscriber) => subscriber,
+        Err(reply) => return Ok(reply),
+    };
+
+    match emails::remove_email(subscriber.email).await {
+        Ok(output) => Ok(json_reply(StatusCode::OK, output)),
+        Err(err) => Ok(json_reply(StatusCode::INTERNAL_SERVER_ERROR, err)),
+    }
+}
+
+pub async fn handle_list_lists(authorization: Option<String>) -> Result<impl warp::Reply, Infallible> {
+    debug!("handling lists request...");
+    let config: Config = match authorise(authorization) {
+        Ok(config) => config,
+        Err(reply) => return Ok(reply),
+    };
+    let pool: MySqlPool = connect(&config).await;
+
+    match subscribers::list_sizes(&pool).await {
+        Ok(lists) => Ok(data_reply(StatusCode::OK, &lists)),
+        Err(err) => Ok(json_reply(StatusCode::INTERNAL_SERVER_ERROR, err)),
+    }
+}
+
+pub async fn handle_add_member(list: String, email: String, authorization: Option<String>) -> Result<impl warp::Reply, Infallible> {
+    debug!("handling list add request...");
+    let config: Config = match authorise(authorization) {
+        Ok(config) => config,
+        Err(reply) => return Ok(reply),
+    };
+    let pool: MySqlPool = connect(&config).await;
+    let list: String = decode(&list);
+    let subscriber: Subscriber = match find_subscriber(&pool, &email).await {
+        Ok(subscriber) => subscriber,
+        Err(reply) => return Ok(reply),
+    };
+    if subscriber.lists.contains(&list) {
+        return Ok(json_reply(StatusCode::OK, format!("{} is already on {}", subscriber.email, list)));
+    }
+
+    let mut connection = match pool.acquire().await {
+        Ok(connection) => connection,
+        Err(err) => return Ok(json_reply(StatusCode::INTERNAL_SERVER_ERROR, format!("Error connecting to the database: {}", err))),
+    };
+    match emails::add_to_list(&mut connection, &subscriber.token, &list).await {
+        Ok(_) => Ok(json_reply(StatusCode::CREATED, format!("Added {} to {}", subscriber.email, list))),
+        Err(err) => Ok(json_reply(StatusCode::INTERNAL_SERVER_ERROR, err)),
+    }
+}
+
+pub async fn handle_remove_member(list: String, email: String, authorization: Option<String>) -> Result<impl warp::Reply, Infallible> {
+    debug!("handling list remove request...");
+    let config: Config = match authorise(authorization) {
+        Ok(config) => config,
+        Err(reply) => return Ok(reply),
+    };
+    let pool: MySqlPool = connect(&config).await;
+    let list: String = decode(&list);
+    let subscriber: Subscriber = match find_subscriber(&pool, &email).await {
+        Ok(subscriber) => subscriber,
+        Err(reply) => return Ok(reply),
+    };
+    if !subscriber.lists.contains(&list) {
+        return Ok(json_reply(StatusCode::NOT_FOUND, format!("{} isn't on {}", subscriber.email, list)));
+    }
+
+    let mut connection = match pool.acquire().await {
+        Ok(connection) => connection,
+        Err(err) => return Ok(json_reply(StatusCode::INTERNAL_SERVER_ERROR, format!("Error connecting to the database: {}", err))),
+    };
+    match emails::remove_from_list(&mut connection, &subscriber.token, &list).await {
+        Ok(_) => Ok(json_reply(StatusCode::OK, format!("Removed {} from {}", subscriber.email, list))),
+        Err(err) => Ok(json_reply(StatusCode::INTERNAL_SERVER_ERROR, err)),
+    }
+}
+
+/// A job to create, the same as -j with -s, -t, --segment and --dry-run. `delay` is in
+/// minutes and `send_at` is a time the same as the front matter's, with neither the
+/// newsletter's send_at or now is used.
+#[derive(Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct NewJob {
+    pub newsletter: String,
+    pub subject: Option<String>,
+    pub delay: Option<i64>,
+    pub send_at: Option<String>,
+    pub segment: Option<String>,
+    pub dry_run: bool,
+    pub archive: Option<bool>,
+}
+
+impl NewJob {
+    /// Newsletters are read from the newsletter directory, so the name can't lead out of it.
+    pub fn check_newsletter(&self) -> Result<(), String> {
+        if self.newsletter.is_empty() || self.newsletter.contains('/') || self.newsletter.contains('\\') || self.newsletter.contains("..") {
+            return Err(format!("Invalid newsletter {:?}, give the name of a file in the newsletter directory", self.newsletter));
+        }
+        Ok(())
+    }
+
+    /// The time to send the job, if one was given.
+    pub fn time(&self, now: i64) -> Result<Option<i64>, String> {
+        match (&self.send_at, self.delay) {
+            (Some(send_at), _) => match parse_time(send_at) {
+                Some(time) => Ok(Some(time)),
+                None => Err(format!("Invalid send_at {:?}", send_at)),
+            },
+            (None, Some(delay)) => match delay.checked_mul(60).and_then(|delay| now.checked_add(delay)) {
+                Some(time) => Ok(Some(time)),
+                None => Err(format!("Invalid delay {}", delay)),
+            },
+            (None, None) => Ok(None),
+        }
+    }
+}
+
+pub async fn handle_list_jobs(authorization: Option<String>) -> Result<impl warp::Reply, Infallible> {
+    debug!("handling jobs request...");
+    if let Err(reply) = authorise(authorization) {
+        return Ok(reply);
+    }
+
+    match job::list_jobs().await {
+        Ok(jobs) => Ok(data_reply(StatusCode::OK, &jobs)),
+        Err(err) => Ok(json_reply(StatusCode::INTERNAL_SERVER_ERROR, err)),
+    }
+}
+
+pub async fn handle_add_job(authorization: Option<String>, new_job: NewJob) -> Result<impl warp::Reply, Infallible> {
+    debug!("handling job add request...");
+    if let Err(reply) = authorise(authorization) {
+        return Ok(reply);
+    }
+    if let Err(err) = new_job.check_newsletter() {
+        return Ok(json_reply(StatusCode::BAD_REQUEST, err));
+    }
+    let time: Option<i64> = match new_job.time(Utc::now().timestamp()) {
+        Ok(time) => time,
+        Err(err) => return Ok(json_reply(StatusCode::BAD_REQUEST, err)),
+    };
+    match job::list_jobs().await {
+        Ok(jobs) if jobs.iter().any(|job| job.newsletter == new_job.newsletter) => {
+            return Ok(json_reply(StatusCode::CONFLICT, format!("There is already a job open for {}", new_job.newsletter)));
+        },
+        Ok(_) => (),
+        Err(err) => return Ok(json_reply(StatusCode::INTERNAL_SERVER_ERROR, err)),
+    }
+
+    match job::add_job(new_job.newsletter, time, new_job.subject, new_job.segment, new_job.dry_run, new_job.archive).await {
+        Ok(output) => Ok(json_reply(StatusCode::CREATED, output)),
+        Err(err) => Ok(json_reply(StatusCode::BAD_REQUEST, err)),
+    }
+}
+
+pub async fn handle_cancel_job(newsletter: String, authorization: Option<String>) -> Result<impl warp::Reply, Infallible> {
+    debug!("handling job cancel request...");
+    if let Err(reply) = authorise(authorization) {
+        return Ok(reply);
+    }
+    let newsletter: String = decode(&newsletter);
+    match job::list_jobs().await {
+        Ok(jobs) if !jobs.iter().any(|job| job.newsletter == newsletter) => {
+            return Ok(json_reply(StatusCode::NOT_FOUND, format!("There is no current job for {}", newsletter)));
+        },
+        Ok(_) => (),
+        Err(err) => return Ok(json_reply(StatusCode::INTERNAL_SERVER_ERROR, err)),
+    }
+
+    match job::remove_job(newsletter).await {
+        Ok(output) => Ok(json_reply(StatusCode::OK, output)),
+        Err(err) => Ok(json_reply(StatusCode::INTERNAL_SERVER_ERROR, err)),
+    }
+}
+
+pub async fn handle_stats(authorization: Option<String>) -> Result<impl warp::Reply, Infallible> {
+    debug!("handling stats request...");
+    if let Err(reply) = authorise(authorization) {
+        return Ok(reply);
+    }
+
+    match job::delivery_stats().await {
+        Ok(stats) => Ok(data_reply(StatusCode::OK, &stats)),
+        Err(err) => Ok(json_reply(StatusCode::INTERNAL_SERVER_ERROR, err)),
+    }
+}
+
+/// Every admin endpoint, each one needs a bearer token from `api_tokens`.
+pub fn admin_route() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone{
+    let authorization = warp::header::optional::<String>("authorization");
+
+    let list_subscribers = warp::path!("api" / "subscribers")
+        .and(warp::get())
+        .and(authorization)
+        .and(warp::query::<SubscriberQuery>())
+        .and_then(handle_list_subscribers);
+    let add_subscriber = warp::path!("api" / "subscribers")
+        .and(warp::post())
+        .and(authorization)
+        .and(content_length_limit(1024 * 16))
+        .and(warp::body::json())
+        .and_then(handle_add_subscriber);
+    let get_subscriber = warp::path!("api" / "subscribers" / String)
+        .and(warp::get())
+        .and(authorization)
+        .and_then(handle_get_subscriber);
+    let update_subscriber = warp::path!("api" / "subscribers" / String)
+        .and(warp::patch())
+        .and(authorization)
+        .and(content_length_limit(1024 * 16))
+        .and(warp::body::json())
+        .and_then(handle_update_subscriber);
+    let remove_subscriber = warp::path!("api" / "subscribers" / String)
+        .and(warp::delete())
+        .and(authorization)
+        .and_then(handle_remove_subscriber);
+
+    let list_lists = warp::path!("api" / "lists")
+        .and(warp::get())
+        .and(authorization)
+        .and_then(handle_list_lists);
+    let add_member = warp::path!("api" / "lists" / String / String)
+        .and(warp::put())
+        .and(authorization)
+        .and_then(handle_add_member);
+    let remove_member = warp::path!("api" / "lists" / String / String)
+        .and(warp::delete())
+        .and(authorization)
+        .and_then(handle_remove_member);
+
+    let list_jobs = warp::path!("api" / "jobs")
+        .and(warp::get())
+        .and(authorization)
+        .and_then(handle_list_jobs);
+    let add_job = warp::path!("api" / "jobs")
+        .and(warp::post())
+        .and(authorization)
+        .and(content_length_limit(1024 * 16))
+        .and(warp::body::json())
+        .and_then(handle_add_job);
+    let cancel_job = warp::path!("api" / "jobs" / String)
+        .and(warp::delete())
+        .and(authorization)
+        .and_then(handle_cancel_job);
+
+    let stats = warp::path!("api" / "stats")
+        .and(warp::get())
+        .and(authorization)
+        .and_then(handle_stats);
+
+    list_subscribers
+        .or(add_subscriber)
+        .or(get_subscriber)
+        .or(update_subscriber)
+        .or(remove_subscriber)
+        .or(list_lists)
+        .or(add_member)
+        .or(remove_member)
+        .or(list_jobs)
+        .or(add_job)
+        .or(cancel_job)
+        .or(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_path_segments() {
+        assert_eq!("example@test.com", decode("example@test.com"));
+        assert_eq!("\"john smith\"@test.com", decode("%22john%20smith%22@test.com"));
+        assert_eq!("100%", decode("100%"));
+        assert_eq!("%zz", decode("%zz"));
+    }
+
+    #[test]
+    fn job_send_time() {
+        let now = 1_000_000;
+        let job = |delay, send_at: Option<&str>| NewJob {
+            newsletter: String::from("october"),
+            delay,
+            send_at: send_at.map(String::from),
+            ..Default::default()
+        };
+
+        assert_eq!(Ok(None), job(None, None).time(now));
+        assert_eq!(Ok(Some(now + 600)), job(Some(10), None).time(now));
+        assert_eq!(Ok(Some(1767225600)), job(Some(10), Some("2026-01-01")).time(now));
+        assert!(job(None, Some("tomorrow")).time(now).is_err());
+        assert!(job(Some(i64::MAX), None).time(now).is_err());
+    }
+
+    #[test]
+    fn check_newsletter_names() {
+        let job = |newsletter: &str| NewJob { newsletter: newsletter.to_string(), ..Default::default() };
+
+        assert_eq!(Ok(()), job("october.md").check_newsletter());
+        for newsletter in ["", "../../etc/passwd", "drafts/october.md", "..", "/etc/passwd"] {
+            assert!(job(newsletter).check_newsletter().is_err(), "{:?} should be invalid", newsletter);
+        }
+    }
+}
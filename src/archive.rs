@@ -0,0 +1,244 @@
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use sqlx::mysql::{MySqlPool, MySqlPoolOptions};
+use warp::{Filter, http::StatusCode};
+use chrono::{TimeZone, Utc};
+use log::debug;
+
+use crate::Config;
+use crate::job::Job;
+use crate::newsletter::{self, escape_html, fill, Newsletter};
+use crate::preferences;
+
+/// A sent newsletter as it's shown in the archive, rendered without any subscriber's
+/// values.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Issue {
+    pub id: i64,
+    pub newsletter: String,
+    pub subject: String,
+    pub preheader: Option<String>,
+    pub body: String,
+    pub sent: i64,
+}
+
+/// An issue without its body, for listing.
+#[derive(Debug, Clone, PartialEq)]
+pub struct IssueSummary {
+    pub id: i64,
+    pub newsletter: String,
+    pub subject: String,
+    pub preheader: Option<String>,
+    pub sent: i64,
+}
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn base64(bytes: &[u8]) -> String {
+    let mut output: String = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let triple: u32 = chunk.iter().enumerate().fold(0, |triple, (i, byte)| triple | (*byte as u32) << (16 - i * 8));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                output.push(BASE64[(triple >> (18 - i * 6)) as usize & 63] as char);
+            } else {
+                output.push('=');
+            }
+        }
+    }
+    output
+}
+
+/// The newsletter's body with every placeholder left at its default, so no subscriber's
+/// details end up in it, and its inline images embedded as data urls so the issue
+/// still shows if the files change.
+pub fn archived_body(newsletter: &Newsletter) -> Result<String, String> {
+    let mut body: String = fill(&newsletter.body, &BTreeMap::new(), true);
+    for image in &newsletter.attachments {
+        let content_id: &str = match &image.content_id {
+            Some(content_id) => content_id,
+            None => continue,
+        };
+        let bytes: Vec<u8> = match std::fs::read(&image.path) {
+            Ok(bytes) => bytes,
+            Err(err) => return Err(format!("Cannot open image {}: {}", image.path, err)),
+        };
+        let mime = mime_guess::from_path(&image.path).first_or_octet_stream();
+        body = body.replace(&format!("cid:{}", content_id), &format!("data:{};base64,{}", mime, base64(&bytes)));
+    }
+    Ok(body)
+}
+
+/// Adds a job that has just been sent to the archive.
+pub async fn publish(pool: &MySqlPool, job: &Job) -> Result<(), String> {
+    let loaded: Newsletter = newsletter::load(&job.newsletter)?;
+    let body: String = archived_body(&loaded)?;
+    let subject: String = fill(&job.subject, &BTreeMap::new(), false);
+    let preheader: Option<String> = loaded.front_matter.preheader.map(|preheader| fill(&preheader, &BTreeMap::new(), false));
+
+    match sqlx::query!(r#"
+                       INSERT INTO issues (job, newsletter, subject, preheader, body, sent) VALUES (?, ?, ?, ?, ?, ?)"#,
+                       job.id,
+                       job.newsletter,
+                       subject,
+                       preheader,
+                       body,
+                       Utc::now().timestamp())
+        .execute(pool)
+        .await {
+            Ok(_) => Ok(()),
+            Err(err) => Err(format!("Error saving the issue: {}", err)),
+        }
+}
+
+pub async fn load_issues(pool: &MySqlPool) -> Result<Vec<IssueSummary>, String> {
+    sqlx::query_as!(IssueSummary, r#"
+                    SELECT id, newsletter, subject, preheader, sent FROM issues ORDER BY sent DESC"#)
+        .fetch_all(pool)
+        .await
+        .map_err(|err| format!("Error reading the archive: {}", err))
+}
+
+/// The `limit` newest issues, for the feeds.
+pub async fn load_latest_issues(pool: &MySqlPool, limit: i64) -> Result<Vec<IssueSummary>, String> {
+    sqlx::query_as!(IssueSummary, r#"
+                    SELECT id, newsletter, subject, preheader, sent FROM issues ORDER BY sent DESC LIMIT ?"#,
+                    limit)
+        .fetch_all(pool)
+        .await
+        .map_err(|err| format!("Error reading the archive: {}", err))
+}
+
+pub async fn load_issue(pool: &MySqlPool, id: i64) -> Result<Option<Issue>, String> {
+    sqlx::query_as!(Issue, r#"
+                    SELECT id, newsletter, subject, preheader, body, sent FROM issues WHERE id = (?)"#,
+                    id)
+        .fetch_optional(pool)
+        .await
+        .map_err(|err| format!("Error reading issue {}: {}", id, err))
+}
+
+pub fn issue_date(sent: i64) -> String {
+    match Utc.timestamp_opt(sent, 0).single() {
+        Some(time) => time.format("%Y-%m-%d").to_string(),
+        None => String::new(),
+    }
+}
+
+/// The archive's index page, newest issue first.
+pub fn render_index(issues: &[IssueSummary], config: &Config) -> String {
+    let mut content: String = String::new();
+    if issues.is_empty() {
+        content.push_str("<p>Nothing has been sent yet.</p>\n");
+    } else {
+        content.push_str("<ul>\n");
+        for issue in issues {
+            content.push_str(&format!("<li><a href=\"{}archive/{}\">{}</a> {}",
+                                      escape_html(&config.api_endpoint),
+                                      issue.id,
+                                      escape_html(&issue.subject),
+                                      issue_date(issue.sent)));
+            if let Some(preheader) = &issue.preheader {
+                content.push_str(&format!("<br>{}", escape_html(preheader)));
+            }
+            content.push_str("</li>\n");
+        }
+        content.push_str("</ul>\n");
+    }
+    content.push_str(&format!("<p>Follow by <a href=\"{}archive/feed.atom\">Atom</a> or <a href=\"{}archive/feed.rss\">RSS</a>.</p>\n",
+                              escape_html(&config.api_endpoint),
+                              escape_html(&config.api_endpoint)));
+    preferences::page(&config.archive_title, &content)
+}
+
+fn html_reply(status: StatusCode, html: String) -> warp::reply::WithStatus<warp::reply::Html<String>> {
+    warp::reply::with_status(warp::reply::html(html), status)
+}
+
+fn error_page(err: String) -> warp::reply::WithStatus<warp::reply::Html<String>> {
+    debug!("{}", err);
+    html_reply(StatusCode::INTERNAL_SERVER_ERROR, preferences::page("Something went wrong",
+                                                                    "<p>The archive can't be shown right now, please try again later.</p>"))
+}
+
+async fn connect(config: &Config) -> MySqlPool {
+    MySqlPoolOptions::new()
+        .max_connections(5)
+        .connect(&config.url)
+        .await
+        .expect("Cannot connect to database!")
+}
+
+pub async fn handle_archive_index() -> Result<impl warp::Reply, Infallible> {
+    debug!("handling archive request...");
+    let config: Config = Config::load_config().unwrap();
+    let pool: MySqlPool = connect(&config).await;
+    match load_issues(&pool).await {
+        Ok(issues) => Ok(html_reply(StatusCode::OK, render_index(&issues, &config))),
+        Err(err) => Ok(error_page(err)),
+    }
+}
+
+/// Issues are shown as they were sent, the newsletter is already a whole HTML page.
+pub async fn handle_archive_issue(id: i64) -> Result<impl warp::Reply, Infallible> {
+    debug!("handling archive issue request...");
+    let config: Config = Config::load_config().unwrap();
+    let pool: MySqlPool = connect(&config).await;
+    match load_issue(&pool, id).await {
+        Ok(Some(issue)) => Ok(html_reply(StatusCode::OK, issue.body)),
+        Ok(None) => Ok(html_reply(StatusCode::NOT_FOUND, preferences::page("Issue not found",
+                                                                           "<p>There is no issue with this address in the archive.</p>"))),
+        Err(err) => Ok(error_page(err)),
+    }
+}
+
+pub fn archive_route() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone{
+    let index = warp::path!("archive")
+        .and(warp::get())
+        .and_then(handle_archive_index);
+    let issue = warp::path!("archive" / i64)
+        .and(warp::get())
+        .and_then(handle_archive_issue);
+
+    index.or(issue)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::newsletter::parse;
+
+    #[test]
+    fn encode_base64() {
+        assert_eq!("", base64(b""));
+        assert_eq!("Zg==", base64(b"f"));
+        assert_eq!("Zm8=", base64(b"fo"));
+        assert_eq!("Zm9v", base64(b"foo"));
+        assert_eq!("Zm9vYmFy", base64(b"foobar"));
+    }
+
+    #[test]
+    fn archive_without_personal_data() {
+        let newsletter = parse("<p>Hi {{ name | there }}, you are {{ email }}</p>\n<a href=\"http://example.com/{{ token }}\">link</a>").unwrap();
+        let body = archived_body(&newsletter).unwrap();
+
+        assert_eq!("<p>Hi there, you are </p>\n<a href=\"http://example.com/\">link</a>", body);
+    }
+
+    #[test]
+    fn render_archive_index() {
+        let config = Config { api_endpoint: String::from("https://news.example.com/"), ..Default::default() };
+        let issues = vec![IssueSummary {
+            id: 2,
+            newsletter: String::from("october.html"),
+            subject: String::from("October <news>"),
+            preheader: Some(String::from("What happened")),
+            sent: 1767225600,
+        }];
+
+        let html = render_index(&issues, &config);
+        assert!(html.contains("<a href=\"https://news.example.com/archive/2\">October &lt;news&gt;</a> 2026-01-01<br>What happened</li>"));
+        assert!(render_index(&[], &config).contains("Nothing has been sent yet."));
+        assert!(html.contains("<a href=\"https://news.example.com/archive/feed.atom\">Atom</a>"));
+    }
+}
@@ -0,0 +1,389 @@
+use std::path::{Path, PathBuf};
+use sha2::{Digest, Sha256};
+use sqlx::mysql::{MySqlPool, MySqlPoolOptions};
+use chrono::Utc;
+use log::debug;
+
+use crate::Config;
+use crate::emails::normalise_email;
+use crate::suppression;
+
+/// Header added to every sent email so bounces can be matched back to their job.
+pub const JOB_HEADER: &str = "X-Newsman-Job";
+
+/// One failed recipient reported by a delivery status notification. The message id
+/// identifies the report, so the same one is only recorded once.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Bounce {
+    pub email: String,
+    pub token: Option<String>,
+    pub job: Option<i64>,
+    pub hard: bool,
+    pub status: String,
+    pub message_id: Option<String>,
+}
+
+/// Builds the per-recipient return path `local+<token>-<job>@domain` from the configured
+/// `verp_address`.
+pub fn encode_verp(verp_address: &str, token: &str, job: i64) -> Option<String> {
+    let (local, domain) = verp_address.rsplit_once('@')?;
+    Some(format!("{}+{}-{}@{}", local, token, job, domain))
+}
+
+/// Reverses `encode_verp`, returning the subscriber token and job an address was sent
+/// for, if it is a return path built from `verp_address`.
+pub fn decode_verp(verp_address: &str, address: &str) -> Option<(String, i64)> {
+    let (local, domain) = verp_address.rsplit_once('@')?;
+    let (address_local, address_domain) = address.trim().trim_matches(['<', '>']).rsplit_once('@')?;
+    if !address_domain.eq_ignore_ascii_case(domain) {
+        return None;
+    }
+    let tag: &str = address_local.strip_prefix(local)?.strip_prefix('+')?;
+    let (token, job) = tag.rsplit_once('-')?;
+    Some((token.to_string(), job.parse().ok()?))
+}
+
+/// Joins folded header lines back onto the line they continue.
+fn unfold(message: &str) -> Vec<String> {
+    let mut lines: Vec<String> = Vec::new();
+    for line in message.lines() {
+        if line.starts_with([' ', '\t']) && !line.trim().is_empty() {
+            if let Some(last) = lines.last_mut() {
+                last.push(' ');
+                last.push_str(line.trim());
+                continue;
+            }
+        }
+        lines.push(line.to_string());
+    }
+    lines
+}
+
+fn field<'a>(line: &'a str, name: &str) -> Option<&'a str> {
+    let (key, value) = line.split_once(':')?;
+    if key.trim().eq_ignore_ascii_case(name) {
+        Some(value.trim())
+    } else {
+        None
+    }
+}
+
+/// Reads the per-recipient fields of an RFC 3464 delivery status notification, returning
+/// a bounce for every recipient that failed or was delayed. When the notification was
+/// sent to a VERP return path the token and job are taken from it. Notifications without
+/// a Message-ID are identified by a hash of their contents. Anything that isn't a DSN
+/// returns no bounces.
+pub fn parse_dsn(message: &str, verp_address: Option<&str>) -> Vec<Bounce> {
+    let lower: String = message.to_lowercase();
+    if !lower.contains("message/delivery-status") {
+        return Vec::new();
+    }
+
+    let mut job: Option<i64> = None;
+    let mut message_id: Option<String> = None;
+    let mut verp: Option<(String, i64)> = None;
+    let mut recipients: Vec<(String, String, String)> = Vec::new();
+    for line in unfold(message) {
+        if let Some(value) = field(&line, JOB_HEADER) {
+            job = value.parse().ok();
+        } else if let Some(value) = field(&line, "Message-ID") {
+            // the notification's own comes first, the original email's can follow it
+            message_id = message_id.or(Some(value.trim_matches(['<', '>']).to_string()));
+        } else if let Some(value) = ["To", "Delivered-To", "X-Original-To", "Envelope-To", "Return-Path"]
+            .iter()
+            .find_map(|name| field(&line, name)) {
+                if let Some(verp_address) = verp_address {
+                    verp = verp.or(decode_verp(verp_address, value));
+                }
+        } else if let Some(value) = field(&line, "Final-Recipient") {
+            let address: &str = value.split_once(';').map(|(_, address)| address).unwrap_or(value);
+            recipients.push((address.trim().trim_matches(['<', '>']).to_string(), String::new(), String::new()));
+        } else if let Some(recipient) = recipients.last_mut() {
+            if let Some(value) = field(&line, "Action") {
+                recipient.1 = value.to_lowercase();
+            } else if let Some(value) = field(&line, "Status") {
+                recipient.2 = value.split_whitespace().next().unwrap_or("").to_string();
+            }
+        }
+    }
+
+    let (token, job) = match verp {
+        Some((token, job)) => (Some(token), Some(job)),
+        None => (None, job),
+    };
+    let message_id: String = message_id.unwrap_or_else(|| format!("sha256:{:x}", Sha256::digest(message.as_bytes())));
+
+    recipients
+        .into_iter()
+        .filter(|(_, action, _)| action == "failed" || action == "delayed")
+        .filter_map(|(email, action, status)| {
+            let email: String = normalise_email(&email).ok()?;
+            let hard: bool = action == "failed" && !status.starts_with('4');
+            Some(Bounce { email, token: token.clone(), job, hard, status, message_id: Some(message_id.clone()) })
+        })
+        .collect()
+}
+
+/// Splits an mbox into its messages on the `From ` separator lines.
+fn split_mbox(mbox: &str) -> Vec<String> {
+    let mut messages: Vec<String> = Vec::new();
+    for line in mbox.lines() {
+        if line.starts_with("From ") {
+            messages.push(String::new());
+        } else if let Some(message) = messages.last_mut() {
+            message.push_str(line.strip_prefix('>').filter(|line| line.starts_with("From ")).unwrap_or(line));
+            message.push('\n');
+        }
+    }
+    messages
+}
+
+/// Swaps the reported recipient for the subscriber the VERP token belongs to, since
+/// relays can rewrite the recipient in the notification.
+async fn attribute(pool: &MySqlPool, mut bounce: Bounce) -> Bounce {
+    if let Some(token) = &bounce.token {
+        if let Ok(Some(record)) = sqlx::query!(r#"
+                                               SELECT email FROM mailing_list WHERE token = (?)"#,
+                                               token)
+            .fetch_optional(pool)
+            .await {
+                bounce.email = record.email;
+            }
+    }
+    bounce
+}
+
+/// Records a bounce and suppresses the email once it reaches the bounce limit. A bounce
+/// from a report that was already recorded is ignored, so reading the same report twice
+/// doesn't count it twice.
+pub async fn record_bounce(pool: &MySqlPool, bounce: &Bounce, config: &Config) -> Result<(), String> {
+    let kind: &str = if bounce.hard { "hard" } else { "soft" };
+    debug!("{} bounce for {} ({})", kind, bounce.email, bounce.status);
+
+    match sqlx::query!(r#"
+                       INSERT IGNORE INTO bounces (email, job, kind, status, time, message_id) VALUES (?, ?, ?, ?, ?, ?)"#,
+                       bounce.email,
+                       bounce.job,
+                       kind,
+                       bounce.status,
+                       Utc::now().timestamp(),
+                       bounce.message_id)
+        .execute(pool)
+        .await {
+            Ok(result) if result.rows_affected() == 0 => {
+                debug!("already recorded {:?} for {}", bounce.message_id, bounce.email);
+                return Ok(());
+            },
+            Ok(_) => (),
+            Err(err) => return Err(format!("Error recording bounce for {}: {}", bounce.email, err)),
+        }
+
+    let count: i64 = match sqlx::query!(r#"
+                                        SELECT COUNT(*) AS count FROM bounces WHERE email = (?) AND kind = (?)"#,
+                                        bounce.email,
+                                        kind)
+        .fetch_one(pool)
+        .await {
+            Ok(record) => record.count,
+            Err(err) => return Err(format!("Error counting bounces for {}: {}", bounce.email, err)),
+        };
+
+    let limit: u32 = if bounce.hard { config.hard_bounce_limit } else { config.soft_bounce_limit };
+    if count >= limit as i64 {
+        suppression::suppress(pool, &bounce.email, suppression::BOUNCED).await?;
+    }
+
+    Ok(())
+}
+
+/// Records a bounce or complaint reported by a relay's webhook, complaints suppress the
+/// email straight away.
+pub async fn record_event(kind: &str, bounce: Bounce) -> Result<String, String> {
+    let config: Config = Config::load_config().unwrap();
+
+    let pool = MySqlPoolOptions::new()
+        .max_connections(5)
+        .connect(&config.url)
+        .await
+        .expect("Cannot connect to database!");
+
+    let bounce: Bounce = attribute(&pool, bounce).await;
+    if kind == "complaint" {
+        suppression::suppress(&pool, &bounce.email, suppression::COMPLAINED).await?;
+        Ok(format!("Recorded complaint from {}", bounce.email))
+    } else {
+        record_bounce(&pool, &bounce, &config).await?;
+        Ok(format!("Recorded bounce for {}", bounce.email))
+    }
+}
+
+/// Unread messages in a maildir, reading them marks them as seen by moving them to `cur`.
+fn maildir_messages(maildir: &str) -> Result<Vec<(PathBuf, String)>, String> {
+    let entries = match std::fs::read_dir(Path::new(maildir).join("new")) {
+        Ok(entries) => entries,
+        Err(err) => return Err(format!("Cannot open maildir {}: {}", maildir, err)),
+    };
+
+    let mut messages: Vec<(PathBuf, String)> = Vec::new();
+    for entry in entries.flatten() {
+        let path: PathBuf = entry.path();
+        match std::fs::read(&path) {
+            Ok(message) => messages.push((path, String::from_utf8_lossy(&message).to_string())),
+            Err(err) => debug!("Cannot read {}: {}", path.display(), err),
+        }
+    }
+    Ok(messages)
+}
+
+fn mark_seen(maildir: &str, path: &Path) {
+    if let Some(name) = path.file_name() {
+        let seen: PathBuf = Path::new(maildir).join("cur").join(format!("{}:2,S", name.to_string_lossy()));
+        if let Err(err) = std::fs::rename(path, &seen) {
+            debug!("Cannot move {} to cur: {}", path.display(), err);
+        }
+    }
+}
+
+/// Moves the mbox aside before it's read, so mail delivered while bounces are processed
+/// goes into a new mbox rather than being lost when it's removed. A file left aside by a
+/// run that failed is read again first, before the mbox is moved.
+fn take_mbox(mbox: &str) -> Result<Option<PathBuf>, String> {
+    let processing: PathBuf = PathBuf::from(format!("{}.processing", mbox));
+    if processing.exists() {
+        debug!("reading {} left by an earlier run", processing.display());
+        return Ok(Some(processing));
+    }
+    match std::fs::rename(mbox, &processing) {
+        Ok(_) => Ok(Some(processing)),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(format!("Cannot move mbox {} aside: {}", mbox, err)),
+    }
+}
+
+/// Reads bounces from the configured maildir and mbox, suppressing addresses once they
+/// pass the hard or soft bounce limit. Processed maildir messages are moved to `cur` and
+/// the processed mbox is removed.
+pub async fn process_bounces() -> Result<String, String> {
+    let config: Config = Config::load_config().unwrap();
+    if config.bounce_maildir.is_none() && config.bounce_mbox.is_none() {
+        return Err(String::from("There is no bounce_maildir or bounce_mbox in the config"));
+    }
+
+    let pool = MySqlPoolOptions::new()
+        .max_connections(5)
+        .connect(&config.url)
+        .await
+        .expect("Cannot connect to database!");
+
+    let mut bounces: usize = 0;
+
+    if let Some(maildir) = config.bounce_maildir.as_deref() {
+        for (path, message) in maildir_messages(maildir)? {
+            for bounce in parse_dsn(&message, config.verp_address.as_deref()) {
+                let bounce: Bounce = attribute(&pool, bounce).await;
+                record_bounce(&pool, &bounce, &config).await?;
+                bounces += 1;
+            }
+            mark_seen(maildir, &path);
+        }
+    }
+
+    if let Some(mbox) = config.bounce_mbox.as_deref() {
+        if let Some(processing) = take_mbox(mbox)? {
+            let contents: String = match std::fs::read(&processing) {
+                Ok(contents) => String::from_utf8_lossy(&contents).to_string(),
+                Err(err) => return Err(format!("Cannot open mbox {}: {}", processing.display(), err)),
+            };
+            for message in split_mbox(&contents) {
+                for bounce in parse_dsn(&message, config.verp_address.as_deref()) {
+                    let bounce: Bounce = attribute(&pool, bounce).await;
+                    record_bounce(&pool, &bounce, &config).await?;
+                    bounces += 1;
+                }
+            }
+            if let Err(err) = std::fs::remove_file(&processing) {
+                return Err(format!("Cannot remove {}: {}", processing.display(), err));
+            }
+        }
+    }
+
+    Ok(format!("Processed {} bounces", bounces))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DSN: &str = "From: MAILER-DAEMON@example.com\n\
+Message-ID: <dsn-1@mail.example.com>\n\
+Content-Type: multipart/report; report-type=delivery-status;\n\
+\tboundary=\"BOUNDARY\"\n\
+\n\
+--BOUNDARY\n\
+Content-Type: text/plain\n\
+\n\
+Delivery failed.\n\
+--BOUNDARY\n\
+Content-Type: message/delivery-status\n\
+\n\
+Reporting-MTA: dns; mail.example.com\n\
+\n\
+Final-Recipient: rfc822; Gone@Test.com\n\
+Action: failed\n\
+Status: 5.1.1\n\
+\n\
+Final-Recipient: rfc822; full@test.com\n\
+Action: failed\n\
+Status: 4.2.2 (mailbox full)\n\
+\n\
+Final-Recipient: rfc822; fine@test.com\n\
+Action: delivered\n\
+Status: 2.0.0\n\
+--BOUNDARY\n\
+Content-Type: text/rfc822-headers\n\
+\n\
+X-Newsman-Job: 42\n\
+Subject: Newsletter\n\
+--BOUNDARY--\n";
+
+    #[test]
+    fn parse_delivery_status() {
+        assert_eq!(vec![
+                   Bounce { email: String::from("Gone@test.com"), token: None, job: Some(42), hard: true, status: String::from("5.1.1"),
+                            message_id: Some(String::from("dsn-1@mail.example.com")) },
+                   Bounce { email: String::from("full@test.com"), token: None, job: Some(42), hard: false, status: String::from("4.2.2"),
+                            message_id: Some(String::from("dsn-1@mail.example.com")) },
+        ], parse_dsn(DSN, None));
+    }
+
+    #[test]
+    fn parse_delivery_status_with_verp() {
+        let dsn = format!("To: bounces+1b4e28ba-2fa1-11d2-883f-0016d3cca427-7@example.com\n{}", DSN);
+        let bounces = parse_dsn(&dsn, Some("bounces@example.com"));
+
+        assert_eq!(Some(String::from("1b4e28ba-2fa1-11d2-883f-0016d3cca427")), bounces[0].token);
+        assert_eq!(Some(7), bounces[0].job);
+    }
+
+    #[test]
+    fn verp_round_trip() {
+        let verp = encode_verp("bounces@example.com", "1b4e28ba-2fa1-11d2-883f-0016d3cca427", 7).unwrap();
+
+        assert_eq!("bounces+1b4e28ba-2fa1-11d2-883f-0016d3cca427-7@example.com", verp);
+        assert_eq!(Some((String::from("1b4e28ba-2fa1-11d2-883f-0016d3cca427"), 7)), decode_verp("bounces@example.com", &verp));
+        assert_eq!(None, decode_verp("bounces@example.com", "someone@example.com"));
+        assert_eq!(None, decode_verp("bounces@example.com", "bounces+1-7@other.com"));
+    }
+
+    #[test]
+    fn ignore_other_messages() {
+        assert!(parse_dsn("Subject: Hello\n\nFinal-Recipient: rfc822; gone@test.com\nAction: failed\n", None).is_empty());
+    }
+
+    #[test]
+    fn split_mbox_messages() {
+        let messages = split_mbox("From a@test.com Mon Jan  1 00:00:00 2026\nSubject: one\n\n>From here\nFrom b@test.com Mon Jan  1 00:00:00 2026\nSubject: two\n");
+
+        assert_eq!(vec![String::from("Subject: one\n\nFrom here\n"), String::from("Subject: two\n")], messages);
+    }
+}
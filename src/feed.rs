@@ -0,0 +1,168 @@
+use std::convert::Infallible;
+use sqlx::mysql::{MySqlPool, MySqlPoolOptions};
+use warp::{Filter, http::StatusCode};
+use chrono::{DateTime, TimeZone, Utc};
+use log::debug;
+
+use crate::Config;
+use crate::archive::{load_latest_issues, IssueSummary};
+use crate::newsletter::escape_html;
+
+/// How many of the latest issues the feeds list.
+const FEED_LENGTH: i64 = 20;
+
+fn time(sent: i64) -> DateTime<Utc> {
+    Utc.timestamp_opt(sent, 0).single().unwrap_or_default()
+}
+
+fn issue_url(config: &Config, issue: &IssueSummary) -> String {
+    format!("{}archive/{}", config.api_endpoint, issue.id)
+}
+
+/// An Atom feed of the issues, which are expected newest first.
+pub fn atom(issues: &[IssueSummary], config: &Config) -> String {
+    let updated: DateTime<Utc> = time(issues.first().map(|issue| issue.sent).unwrap_or_default());
+    let author: &str = config.sender_name.as_deref().unwrap_or(&config.archive_title);
+    let mut feed: String = format!("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n\
+                                    <feed xmlns=\"http://www.w3.org/2005/Atom\">\n\
+                                    <title>{}</title>\n\
+                                    <link href=\"{}archive\"/>\n\
+                                    <link rel=\"self\" href=\"{}archive/feed.atom\"/>\n\
+                                    <id>{}archive</id>\n\
+                                    <updated>{}</updated>\n\
+                                    <author><name>{}</name></author>\n",
+                                   escape_html(&config.archive_title),
+                                   escape_html(&config.api_endpoint),
+                                   escape_html(&config.api_endpoint),
+                                   escape_html(&config.api_endpoint),
+                                   updated.to_rfc3339(),
+                                   escape_html(author));
+    for issue in issues {
+        let url: String = escape_html(&issue_url(config, issue));
+        feed.push_str(&format!("<entry>\n<title>{}</title>\n<link href=\"{}\"/>\n<id>{}</id>\n<updated>{}</updated>\n",
+                               escape_html(&issue.subject),
+                               url,
+                               url,
+                               time(issue.sent).to_rfc3339()));
+        if let Some(preheader) = &issue.preheader {
+            feed.push_str(&format!("<summary>{}</summary>\n", escape_html(preheader)));
+        }
+        feed.push_str("</entry>\n");
+    }
+    feed.push_str("</feed>\n");
+    feed
+}
+
+/// An RSS 2.0 feed of the issues, which are expected newest first.
+pub fn rss(issues: &[IssueSummary], config: &Config) -> String {
+    let mut feed: String = format!("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n\
+                                    <rss version=\"2.0\">\n<channel>\n\
+                                    <title>{}</title>\n\
+                                    <link>{}archive</link>\n\
+                                    <description>{}</description>\n",
+                                   escape_html(&config.archive_title),
+                                   escape_html(&config.api_endpoint),
+                                   escape_html(&config.archive_title));
+    if let Some(issue) = issues.first() {
+        feed.push_str(&format!("<lastBuildDate>{}</lastBuildDate>\n", time(issue.sent).to_rfc2822()));
+    }
+    for issue in issues {
+        let url: String = escape_html(&issue_url(config, issue));
+        feed.push_str(&format!("<item>\n<title>{}</title>\n<link>{}</link>\n<guid isPermaLink=\"true\">{}</guid>\n<pubDate>{}</pubDate>\n",
+                               escape_html(&issue.subject),
+                               url,
+                               url,
+                               time(issue.sent).to_rfc2822()));
+        if let Some(preheader) = &issue.preheader {
+            feed.push_str(&format!("<description>{}</description>\n", escape_html(preheader)));
+        }
+        feed.push_str("</item>\n");
+    }
+    feed.push_str("</channel>\n</rss>\n");
+    feed
+}
+
+/// Loads the latest archived issues and renders them with `render`, as `content_type`.
+async fn feed_reply(render: fn(&[IssueSummary], &Config) -> String, content_type: &'static str) -> Result<Box<dyn warp::Reply>, Infallible> {
+    let config: Config = Config::load_config().unwrap();
+
+    let pool: MySqlPool = MySqlPoolOptions::new()
+        .max_connections(5)
+        .connect(&config.url)
+        .await
+        .expect("Cannot connect to database!");
+
+    match load_latest_issues(&pool, FEED_LENGTH).await {
+        Ok(issues) => Ok(Box::new(warp::reply::with_header(render(&issues, &config), "content-type", content_type))),
+        Err(err) => {
+            debug!("{}", err);
+            Ok(Box::new(warp::reply::with_status("The feed can't be shown right now", StatusCode::INTERNAL_SERVER_ERROR)))
+        },
+    }
+}
+
+pub async fn handle_atom() -> Result<Box<dyn warp::Reply>, Infallible> {
+    debug!("handling atom feed request...");
+    feed_reply(atom, "application/atom+xml; charset=utf-8").await
+}
+
+pub async fn handle_rss() -> Result<Box<dyn warp::Reply>, Infallible> {
+    debug!("handling rss feed request...");
+    feed_reply(rss, "application/rss+xml; charset=utf-8").await
+}
+
+pub fn feed_route() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone{
+    let atom = warp::path!("archive" / "feed.atom")
+        .and(warp::get())
+        .and_then(handle_atom);
+    let rss = warp::path!("archive" / "feed.rss")
+        .and(warp::get())
+        .and_then(handle_rss);
+
+    atom.or(rss)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn issues() -> Vec<IssueSummary> {
+        vec![IssueSummary {
+            id: 2,
+            newsletter: String::from("november.html"),
+            subject: String::from("November & more"),
+            preheader: Some(String::from("What happened")),
+            sent: 1767225600,
+        }, IssueSummary {
+            id: 1,
+            newsletter: String::from("october.html"),
+            subject: String::from("October"),
+            preheader: None,
+            sent: 1764547200,
+        }]
+    }
+
+    #[test]
+    fn render_atom() {
+        let config = Config { api_endpoint: String::from("https://news.example.com/"), ..Default::default() };
+        let feed = atom(&issues(), &config);
+
+        assert!(feed.contains("<updated>2026-01-01T00:00:00+00:00</updated>\n<author><name>Newsletter archive</name></author>"));
+        assert!(feed.contains("<entry>\n<title>November &amp; more</title>\n<link href=\"https://news.example.com/archive/2\"/>\n\
+                               <id>https://news.example.com/archive/2</id>\n<updated>2026-01-01T00:00:00+00:00</updated>\n\
+                               <summary>What happened</summary>\n</entry>"));
+        assert!(feed.contains("<id>https://news.example.com/archive/1</id>\n<updated>2025-12-01T00:00:00+00:00</updated>\n</entry>"));
+    }
+
+    #[test]
+    fn render_rss() {
+        let config = Config { api_endpoint: String::from("https://news.example.com/"), ..Default::default() };
+        let feed = rss(&issues(), &config);
+
+        assert!(feed.contains("<lastBuildDate>Thu, 01 Jan 2026 00:00:00 +0000</lastBuildDate>"));
+        assert!(feed.contains("<item>\n<title>November &amp; more</title>\n<link>https://news.example.com/archive/2</link>\n\
+                               <guid isPermaLink=\"true\">https://news.example.com/archive/2</guid>\n\
+                               <pubDate>Thu, 01 Jan 2026 00:00:00 +0000</pubDate>\n<description>What happened</description>\n</item>"));
+        assert!(rss(&[], &config).ends_with("<description>Newsletter archive</description>\n</channel>\n</rss>\n"));
+    }
+}
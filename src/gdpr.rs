@@ -0,0 +1,207 @@
+use serde::Serialize;
+use sqlx::mysql::{MySqlPool, MySqlPoolOptions};
+use log::debug;
+
+use crate::Config;
+use crate::emails::{delete_subscriber, normalise_email};
+use crate::subscribers::{load_subscriber, Subscriber};
+use crate::suppression;
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Suppression {
+    pub reason: String,
+    pub time: i64,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Delivery {
+    pub job: i64,
+    pub newsletter: String,
+    pub status: String,
+    pub time: i64,
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Event {
+    pub kind: String,
+    pub job: Option<i64>,
+    pub status: String,
+    pub time: i64,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Open {
+    pub job: i64,
+    pub time: i64,
+}
+
+/// Everything newsman stores about an email, for answering subject access requests.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct SubjectData {
+    pub email: String,
+    pub subscriber: Option<Subscriber>,
+    pub suppression: Option<Suppression>,
+    pub deliveries: Vec<Delivery>,
+    pub events: Vec<Event>,
+    pub opens: Vec<Open>,
+}
+
+impl SubjectData {
+    pub fn is_empty(&self) -> bool {
+        self.subscriber.is_none() && self.suppression.is_none() && self.deliveries.is_empty() && self.events.is_empty()
+    }
+}
+
+async fn collect_with(pool: &MySqlPool, email: String) -> Result<SubjectData, String> {
+    let subscriber: Option<Subscriber> = load_subscriber(pool, &email).await?;
+
+    let suppression: Option<Suppression> = match sqlx::query!(r#"
+                                                              SELECT reason, time FROM suppressions WHERE hash = (?)"#,
+                                                              suppression::hash_email(&email))
+        .fetch_optional(pool)
+        .await {
+            Ok(record) => record.map(|record| Suppression { reason: record.reason, time: record.time }),
+            Err(err) => return Err(format!("Error reading suppression list: {}", err)),
+        };
+
+    let deliveries: Vec<Delivery> = match sqlx::query!(r#"
+                                                       SELECT job, newsletter, status, time, error FROM deliveries WHERE email = (?) ORDER BY time"#,
+                                                       email)
+        .fetch_all(pool)
+        .await {
+            Ok(records) => records.into_iter().map(|record| Delivery {
+                job: record.job,
+                newsletter: record.newsletter,
+                status: record.status,
+                time: record.time,
+                error: record.error,
+            }).collect(),
+            Err(err) => return Err(format!("Error reading deliveries: {}", err)),
+        };
+
+    let events: Vec<Event> = match sqlx::query!(r#"
+                                                SELECT kind, job, status, time FROM bounces WHERE email = (?) ORDER BY time"#,
+                                                email)
+        .fetch_all(pool)
+        .await {
+            Ok(records) => records.into_iter().map(|record| Event {
+                kind: record.kind,
+                job: record.job,
+                status: record.status,
+                time: record.time,
+            }).collect(),
+            Err(err) => return Err(format!("Error reading bounces: {}", err)),
+        };
+
+    let opens: Vec<Open> = match &subscriber {
+        Some(subscriber) => match sqlx::query!(r#"
+                                               SELECT job, time FROM opens WHERE token = (?) ORDER BY time"#,
+                                               subscriber.token)
+            .fetch_all(pool)
+            .await {
+                Ok(records) => records.into_iter().map(|record| Open { job: record.job, time: record.time }).collect(),
+                Err(err) => return Err(format!("Error reading opens: {}", err)),
+            },
+        None => Vec::new(),
+    };
+
+    Ok(SubjectData { email, subscriber, suppression, deliveries, events, opens })
+}
+
+/// Collects everything stored about an email.
+pub async fn collect(email: String) -> Result<SubjectData, String> {
+    let config: Config = Config::load_config().unwrap();
+    let email: String = normalise_email(&email)?;
+
+    let pool = MySqlPoolOptions::new()
+        .max_connections(5)
+        .connect(&config.url)
+        .await
+        .expect("Cannot connect to database!");
+
+    collect_with(&pool, email).await
+}
+
+pub async fn export_data(email: String, output: Option<String>) -> Result<String, String> {
+    let data: SubjectData = collect(email).await?;
+    if data.is_empty() {
+        return Err(format!("Nothing is stored about {}", data.email));
+    }
+
+    let json: String = match serde_json::to_string_pretty(&data) {
+        Ok(json) => json,
+        Err(err) => return Err(format!("Error writing JSON: {}", err)),
+    };
+    match output {
+        Some(path) => match std::fs::write(&path, json) {
+            Ok(_) => Ok(format!("Exported the data stored about {} to {}", data.email, path)),
+            Err(err) => Err(format!("Cannot write {}: {}", path, err)),
+        },
+        None => Ok(json),
+    }
+}
+
+/// Deletes everything stored about an email apart from a hash of it on the suppression
+/// list, so it isn't added or mailed again.
+pub async fn erase(email: String) -> Result<String, String> {
+    let config: Config = Config::load_config().unwrap();
+    let email: String = normalise_email(&email)?;
+
+    let pool = MySqlPoolOptions::new()
+        .max_connections(5)
+        .connect(&config.url)
+        .await
+        .expect("Cannot connect to database!");
+
+    let data: SubjectData = collect_with(&pool, email.clone()).await?;
+    if data.is_empty() {
+        return Err(format!("Nothing is stored about {}", email));
+    }
+
+    let erased = async {
+        let mut transaction = pool.begin().await?;
+        if let Some(subscriber) = &data.subscriber {
+            debug!("erasing subscriber {}", subscriber.token);
+            delete_subscriber(&mut transaction, &subscriber.token).await?;
+        }
+        sqlx::query!(r#"
+                     DELETE FROM deliveries WHERE email = (?)"#,
+                     email)
+            .execute(&mut transaction)
+            .await?;
+        sqlx::query!(r#"
+                     DELETE FROM bounces WHERE email = (?)"#,
+                     email)
+            .execute(&mut transaction)
+            .await?;
+        suppression::forget(&mut transaction, &email).await?;
+        transaction.commit().await
+    }.await;
+    if let Err(err) = erased {
+        return Err(format!("Error erasing {}, nothing was erased: {}", email, err));
+    }
+
+    Ok(format!("Erased {}, removing {} deliveries and {} events", email, data.deliveries.len(), data.events.len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_subject_data() {
+        let mut data = SubjectData {
+            email: String::from("example@test.com"),
+            subscriber: None,
+            suppression: None,
+            deliveries: Vec::new(),
+            events: Vec::new(),
+            opens: Vec::new(),
+        };
+        assert!(data.is_empty());
+
+        data.suppression = Some(Suppression { reason: String::from(suppression::UNSUBSCRIBED), time: 0 });
+        assert!(!data.is_empty());
+    }
+}
@@ -0,0 +1,235 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use sqlx::mysql::MySqlPoolOptions;
+use serde_json::Value;
+use log::debug;
+
+use crate::Config;
+use crate::emails::{add_subscriber_with, AddError, Added, NewSubscriber};
+
+/// What happened to each row of an import. Existing subscribers are `updated` when the
+/// row gave them a name, lists, tags or fields and `duplicates` when it had nothing new.
+#[derive(Debug, Default, PartialEq)]
+pub struct ImportReport {
+    pub added: usize,
+    pub updated: usize,
+    pub duplicates: usize,
+    pub suppressed: usize,
+    pub invalid: Vec<(usize, String)>,
+}
+
+impl fmt::Display for ImportReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} added, {} updated, {} duplicates, {} suppressed, {} invalid",
+               self.added, self.updated, self.duplicates, self.suppressed, self.invalid.len())?;
+        for (row, err) in &self.invalid {
+            write!(f, "\nrow {}: {}", row, err)?;
+        }
+        Ok(())
+    }
+}
+
+/// Lists and tags in a CSV cell are separated by semicolons.
+fn split_cell(cell: &str) -> Vec<String> {
+    cell.split(';')
+        .map(|item| item.trim().to_string())
+        .filter(|item| !item.is_empty())
+        .collect()
+}
+
+/// Reads subscribers from CSV with a header row, `email`, `name`, `lists` and `tags` are
+/// read as such and any other column, apart from those added by the export, is stored
+/// as a custom field.
+pub fn parse_csv(contents: &str) -> Result<Vec<Result<NewSubscriber, String>>, String> {
+    let mut reader = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_reader(contents.as_bytes());
+    let headers: Vec<String> = match reader.headers() {
+        Ok(headers) => headers.iter().map(|header| header.to_lowercase()).collect(),
+        Err(err) => return Err(format!("Error reading CSV header: {}", err)),
+    };
+    if !headers.iter().any(|header| header == "email") {
+        return Err(String::from("The CSV has no email column"));
+    }
+
+    let mut rows: Vec<Result<NewSubscriber, String>> = Vec::new();
+    for record in reader.records() {
+        let record = match record {
+            Ok(record) => record,
+            Err(err) => {
+                rows.push(Err(format!("Error reading row: {}", err)));
+                continue;
+            }
+        };
+        let mut subscriber = NewSubscriber::default();
+        for (header, cell) in headers.iter().zip(record.iter()) {
+            match header.as_str() {
+                "email" => subscriber.email = cell.to_string(),
+                "name" if !cell.is_empty() => subscriber.name = Some(cell.to_string()),
+                "lists" => subscriber.lists = split_cell(cell),
+                "tags" => subscriber.tags = split_cell(cell),
+                // written by the export, they can't be set on import
+                "token" | "status" | "created" | "locale" | "frequency" => (),
+                _ if !cell.is_empty() => {
+                    subscriber.fields.insert(header.clone(), cell.to_string());
+                },
+                _ => (),
+            }
+        }
+        rows.push(Ok(subscriber));
+    }
+
+    Ok(rows)
+}
+
+fn value_to_string(value: &Value) -> String {
+    match value {
+        Value::String(value) => value.clone(),
+        value => value.to_string(),
+    }
+}
+
+fn value_to_list(value: &Value) -> Vec<String> {
+    match value {
+        Value::Array(values) => values.iter().map(value_to_string).collect(),
+        Value::String(value) => split_cell(value),
+        _ => Vec::new(),
+    }
+}
+
+/// Reads subscribers from a JSON array of objects, `fields` holds the custom fields and
+/// any unknown keys are treated as custom fields too.
+pub fn parse_json(contents: &str) -> Result<Vec<Result<NewSubscriber, String>>, String> {
+    let rows: Vec<Value> = match serde_json::from_str(contents) {
+        Ok(rows) => rows,
+        Err(err) => return Err(format!("Error reading JSON, expected an array of subscribers: {}", err)),
+    };
+
+    Ok(rows.iter().map(|row| {
+        let object = match row.as_object() {
+            Some(object) => object,
+            None => return Err(String::from("Row isn't an object")),
+        };
+        let mut subscriber = NewSubscriber::default();
+        for (key, value) in object {
+            match key.as_str() {
+                "email" => subscriber.email = value_to_string(value),
+                "name" if !value.is_null() => subscriber.name = Some(value_to_string(value)),
+                "lists" => subscriber.lists = value_to_list(value),
+                "tags" => subscriber.tags = value_to_list(value),
+                "token" | "status" | "created" | "locale" | "frequency" => (),
+                "fields" => {
+                    let fields: BTreeMap<String, String> = value
+                        .as_object()
+                        .map(|fields| fields.iter().map(|(name, value)| (name.clone(), value_to_string(value))).collect())
+                        .unwrap_or_default();
+                    subscriber.fields.extend(fields);
+                },
+                _ if !value.is_null() => {
+                    subscriber.fields.insert(key.clone(), value_to_string(value));
+                },
+                _ => (),
+            }
+        }
+        Ok(subscriber)
+    }).collect())
+}
+
+fn has_changes(subscriber: &NewSubscriber) -> bool {
+    subscriber.name.is_some() || !subscriber.lists.is_empty() || !subscriber.tags.is_empty() || !subscriber.fields.is_empty()
+}
+
+/// Imports subscribers from a .csv or .json file, each row is added the same way as -a.
+/// The whole file is imported in one transaction, so nothing is saved if it stops part
+/// way through.
+pub async fn import_subscribers(path: String) -> Result<String, String> {
+    let config: Config = Config::load_config().unwrap();
+    let contents: String = match std::fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(err) => return Err(format!("Cannot open {}: {}", path, err)),
+    };
+
+    let rows = if path.to_lowercase().ends_with(".json") {
+        parse_json(&contents)?
+    } else {
+        parse_csv(&contents)?
+    };
+
+    let pool = MySqlPoolOptions::new()
+        .max_connections(5)
+        .connect(&config.url)
+        .await
+        .expect("Cannot connect to database!");
+
+    let mut transaction = match pool.begin().await {
+        Ok(transaction) => transaction,
+        Err(err) => return Err(format!("Error starting the import: {}", err)),
+    };
+    let mut report = ImportReport::default();
+    for (index, row) in rows.into_iter().enumerate() {
+        // rows are numbered from 1, after the CSV header
+        let row_number: usize = index + 1;
+        let subscriber: NewSubscriber = match row {
+            Ok(subscriber) => subscriber,
+            Err(err) => {
+                report.invalid.push((row_number, err));
+                continue;
+            }
+        };
+        debug!("importing {}", subscriber.email);
+        match add_subscriber_with(&mut transaction, &config, &subscriber, true).await {
+            Ok(Added::New(_)) => report.added += 1,
+            Ok(Added::Existing(_)) if has_changes(&subscriber) => report.updated += 1,
+            Ok(Added::Existing(_)) => report.duplicates += 1,
+            Err(AddError::Suppressed(_)) | Err(AddError::Unsubscribed(_)) => report.suppressed += 1,
+            Err(AddError::Invalid(err)) => report.invalid.push((row_number, format!("{} ({})", err, subscriber.email))),
+            Err(AddError::Database(err)) => return Err(format!("Import stopped at row {}, nothing was imported: {}", row_number, err)),
+        }
+    }
+    if let Err(err) = transaction.commit().await {
+        return Err(format!("Error saving the import, nothing was imported: {}", err));
+    }
+
+    Ok(format!("Imported {}: {}", path, report))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_csv_rows() {
+        let rows = parse_csv("email,name,lists,tags,plan\nexample@test.com,Example,weekly;monthly,beta,pro\nexample2@test.com,,,,\n").unwrap();
+
+        assert_eq!(Ok(NewSubscriber {
+            email: String::from("example@test.com"),
+            name: Some(String::from("Example")),
+            lists: vec![String::from("weekly"), String::from("monthly")],
+            tags: vec![String::from("beta")],
+            fields: BTreeMap::from([(String::from("plan"), String::from("pro"))]),
+        }), rows[0]);
+        assert_eq!(Ok(NewSubscriber { email: String::from("example2@test.com"), ..Default::default() }), rows[1]);
+    }
+
+    #[test]
+    fn parse_csv_without_email() {
+        assert!(parse_csv("name\nExample\n").is_err());
+    }
+
+    #[test]
+    fn parse_json_rows() {
+        let rows = parse_json(r#"[{"email": "example@test.com", "lists": ["weekly"], "fields": {"plan": "pro"}, "country": "DE", "age": 30}, 5]"#).unwrap();
+
+        assert_eq!(Ok(NewSubscriber {
+            email: String::from("example@test.com"),
+            lists: vec![String::from("weekly")],
+            fields: BTreeMap::from([
+                (String::from("age"), String::from("30")),
+                (String::from("country"), String::from("DE")),
+                (String::from("plan"), String::from("pro")),
+            ]),
+            ..Default::default()
+        }), rows[0]);
+        assert!(rows[1].is_err());
+    }
+}
@@ -1,23 +1,65 @@
-use sqlx::mysql::MySqlPoolOptions;
-use log::debug;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use serde::Serialize;
+use sqlx::mysql::{MySqlPool, MySqlPoolOptions};
+use log::{debug, error};
 use chrono::Utc;
 use lettre::transport::smtp::authentication::Credentials; 
 use lettre::{SmtpTransport, Transport};
-use lettre::message::{header::ContentType, Message};
-use tokio::time::{interval, Duration};
+use lettre::address::Envelope;
+use lettre::message::{header::{ContentType, HeaderName, HeaderValue, Headers}, Attachment, Mailbox, Message, MultiPart, SinglePart};
+use tokio::time::{interval, Duration, Instant, Interval, MissedTickBehavior};
+use uuid::Uuid;
 
 use crate::Config;
+use crate::config;
+use crate::archive;
+use crate::bounce;
 use crate::emails::MailingList;
+use crate::metrics;
+use crate::newsletter;
+use crate::preferences;
+use crate::segment::{self, Activity, Segment};
+use crate::subscribers::{load_subscriber, load_subscribers, Subscriber};
 
-#[derive(Clone)]
-struct Job {
-    newsletter: String,
-    time: i64,
-    subject: String,
+/// Subject used when sending a newsletter, the one given on the command line wins over
+/// the front matter.
+fn subject_for(newsletter: &str, subject: Option<String>, front_matter: &newsletter::FrontMatter) -> Result<String, String> {
+    match subject.or(front_matter.subject.clone()) {
+        Some(subject) => Ok(subject),
+        None => Err(format!("No subject for {}, pass -s or set one in its front matter", newsletter)),
+    }
 }
 
-pub async fn add_job(newsletter: String, delay: i64, subject: String) -> Result<String, String>{
+#[derive(Clone, Serialize)]
+pub struct Job {
+    pub id: i64,
+    pub newsletter: String,
+    pub time: i64,
+    pub subject: String,
+    pub list: Option<String>,
+    pub dry_run: bool,
+    pub segment: Option<String>,
+    pub archive: bool,
+}
+
+pub async fn add_job(newsletter: String, delay: Option<i64>, subject: Option<String>, segment: Option<String>, dry_run: bool, archive: Option<bool>) -> Result<String, String>{
     let config: Config = Config::load_config().expect("Cannot open config");
+    let loaded = newsletter::load(&newsletter)?;
+    newsletter::check_attachments(&loaded, &config)?;
+    let front_matter = loaded.front_matter;
+
+    let subject: String = subject_for(&newsletter, subject, &front_matter)?;
+    let delay: i64 = match delay {
+        Some(delay) => delay,
+        None => front_matter.send_time()?.unwrap_or_else(|| Utc::now().timestamp()),
+    };
+    let segment: Option<String> = segment.or(front_matter.segment.clone());
+    if let Some(segment) = &segment {
+        Segment::parse(segment)?;
+    }
+    // issues for a list or segment are only archived when asked for, as they may be
+    // meant for those subscribers alone
+    let archive: bool = archive.or(front_matter.archive).unwrap_or(front_matter.list.is_none() && segment.is_none());
 
     let pool = MySqlPoolOptions::new()
         .max_connections(5)
@@ -37,13 +79,18 @@ pub async fn add_job(newsletter: String, delay: i64, subject: String) -> Result<
         },
         Err(_) => {
             match sqlx::query!(r#"
-                               INSERT INTO jobs (newsletter, time, subject) VALUES (?, ?, ?)"#,
+                               INSERT INTO jobs (newsletter, time, subject, list, dry_run, segment, archive) VALUES (?, ?, ?, ?, ?, ?, ?)"#,
                                newsletter,
                                delay,
-                               subject)
+                               subject,
+                               front_matter.list,
+                               dry_run,
+                               segment,
+                               archive)
                 .execute(&pool)
                 .await {
-                    Ok(_) => Ok(format!("successfully added job")),
+                    Ok(_) if dry_run => Ok(String::from("successfully added dry run job")),
+                    Ok(_) => Ok(String::from("successfully added job")),
                     Err(err) => Err(format!("error adding job: {}", err))
                 }
         }
@@ -71,7 +118,7 @@ pub async fn remove_job(newsletter: String) -> Result<String, String>{
             match sqlx::query!(r#"DELETE FROM jobs WHERE newsletter = (?)"#,newsletter)
                 .execute(&pool)
                 .await {
-                    Ok(_) => Ok(format!("successfully removed job")),
+                    Ok(_) => Ok(String::from("successfully removed job")),
                     Err(err) => Err(format!("error removing job: {}", err))
                 }
         },
@@ -79,38 +126,497 @@ pub async fn remove_job(newsletter: String) -> Result<String, String>{
     }
 }
 
-pub fn execute_job(newsletter: String, subject: String, clients: &Vec<MailingList>) -> Result<(), ()> {
+async fn load_jobs(pool: &MySqlPool) -> Result<Vec<Job>, sqlx::Error> {
+    sqlx::query_as!(Job, r#"
+                SELECT id, newsletter, time, subject, list, dry_run AS `dry_run: bool`, segment, archive AS `archive: bool` FROM jobs"#)
+        .fetch_all(pool)
+        .await
+}
+
+/// Every job that's waiting to be sent.
+pub async fn list_jobs() -> Result<Vec<Job>, String> {
+    let config: Config = Config::load_config().unwrap();
+
+    let pool = MySqlPoolOptions::new()
+        .max_connections(5)
+        .connect(&config.url)
+        .await
+        .expect("Cannot connect to database!");
+
+    load_jobs(&pool).await.map_err(|err| format!("Error getting jobs from database: {}", err))
+}
+
+/// How a sent job went.
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+pub struct JobStats {
+    pub job: i64,
+    pub newsletter: String,
+    pub sent: i64,
+    pub failed: i64,
+    pub dry_run: i64,
+    pub bounced: i64,
+    pub opened: i64,
+}
+
+/// Adds up the delivery counts by job and status along with the bounces and opens
+/// counted for each job.
+fn tally(deliveries: Vec<(i64, String, String, i64)>, bounces: Vec<(i64, i64)>, opens: Vec<(i64, i64)>) -> Vec<JobStats> {
+    let mut stats: BTreeMap<i64, JobStats> = BTreeMap::new();
+    for (job, newsletter, status, count) in deliveries {
+        let entry: &mut JobStats = stats.entry(job).or_insert_with(|| JobStats { job, newsletter, ..Default::default() });
+        match status.as_str() {
+            "sent" => entry.sent += count,
+            "failed" => entry.failed += count,
+            "dry_run" => entry.dry_run += count,
+            _ => (),
+        }
+    }
+    for (job, count) in bounces {
+        if let Some(entry) = stats.get_mut(&job) {
+            entry.bounced += count;
+        }
+    }
+    for (job, count) in opens {
+        if let Some(entry) = stats.get_mut(&job) {
+            entry.opened += count;
+        }
+    }
+
+    stats.into_values().collect()
+}
+
+/// Counts the deliveries, bounces and opens of every job that has been sent.
+pub async fn delivery_stats() -> Result<Vec<JobStats>, String> {
+    let config: Config = Config::load_config().unwrap();
+
+    let pool = MySqlPoolOptions::new()
+        .max_connections(5)
+        .connect(&config.url)
+        .await
+        .expect("Cannot connect to database!");
+
+    let deliveries: Vec<(i64, String, String, i64)> = match sqlx::query!(r#"
+                                                                        SELECT job, newsletter, status, COUNT(*) AS count FROM deliveries
+                                                                        GROUP BY job, newsletter, status ORDER BY job"#)
+        .fetch_all(&pool)
+        .await {
+            Ok(records) => records.into_iter().map(|record| (record.job, record.newsletter, record.status, record.count)).collect(),
+            Err(err) => return Err(format!("Error reading deliveries: {}", err)),
+        };
+
+    let bounces: Vec<(i64, i64)> = match sqlx::query!(r#"
+                                                     SELECT job, COUNT(*) AS count FROM bounces GROUP BY job"#)
+        .fetch_all(&pool)
+        .await {
+            Ok(records) => records.into_iter().filter_map(|record| record.job.map(|job| (job, record.count))).collect(),
+            Err(err) => return Err(format!("Error reading bounces: {}", err)),
+        };
+
+    let opens: Vec<(i64, i64)> = match sqlx::query!(r#"
+                                                   SELECT job, COUNT(*) AS count FROM opens GROUP BY job"#)
+        .fetch_all(&pool)
+        .await {
+            Ok(records) => records.into_iter().map(|record| (record.job, record.count)).collect(),
+            Err(err) => return Err(format!("Error reading opens: {}", err)),
+        };
+
+    Ok(tally(deliveries, bounces, opens))
+}
+
+/// A newsletter loaded and ready to be rendered for each subscriber.
+pub struct Mailing {
+    from: Mailbox,
+    reply_to: Option<Mailbox>,
+    message_id_domain: Option<String>,
+    headers: Headers,
+    subject: String,
+    body: String,
+    files: Vec<(newsletter::Attachment, Vec<u8>, ContentType)>,
+    api_endpoint: String,
+    verp_address: Option<String>,
+    track_opens: bool,
+    job: Option<i64>,
+}
+
+impl Mailing {
+    /// Loads the newsletter and its attachments, with the front matter taking
+    /// precedence over the sender settings in the config.
+    pub fn prepare(newsletter: &str, subject: String, config: &Config) -> Result<Mailing, String> {
+        let newsletter = newsletter::load(newsletter)?;
+        let front_matter = newsletter.front_matter;
+
+        let from_address = front_matter.from_address.unwrap_or(config.sender.clone());
+        let from = match from_address.parse() {
+            Ok(address) => Mailbox::new(front_matter.from_name.or(config.sender_name.clone()), address),
+            Err(err) => return Err(format!("Invalid sender {}: {}", from_address, err)),
+        };
+        let reply_to: Option<Mailbox> = match front_matter.reply_to.or(config.reply_to.clone()) {
+            Some(reply_to) => match reply_to.parse() {
+                Ok(reply_to) => Some(reply_to),
+                Err(err) => return Err(format!("Invalid reply-to {}: {}", reply_to, err)),
+            },
+            None => None,
+        };
+
+        config::check_headers(&front_matter.headers)?;
+        let mut headers: Headers = Headers::new();
+        for (name, value) in config.headers.iter().chain(front_matter.headers.iter()) {
+            match HeaderName::new_from_ascii(name.clone()) {
+                Ok(name) => headers.insert_raw(HeaderValue::new(name, value.clone())),
+                Err(_) => return Err(format!("Invalid header name {}", name)),
+            }
+        }
+
+        let mut files: Vec<(newsletter::Attachment, Vec<u8>, ContentType)> = Vec::new();
+        for attachment in newsletter.attachments {
+            let content: Vec<u8> = match std::fs::read(&attachment.path) {
+                Ok(content) => content,
+                Err(err) => return Err(format!("Cannot open attachment {}: {}", attachment.path, err)),
+            };
+            let content_type = ContentType::parse(mime_guess::from_path(&attachment.path)
+                                                  .first_or_octet_stream()
+                                                  .as_ref())
+                .unwrap();
+            files.push((attachment, content, content_type));
+        }
+
+        let body: String = match front_matter.preheader {
+            Some(preheader) => format!("<span style=\"display:none\">{}</span>\n{}", preheader, newsletter.body),
+            None => newsletter.body,
+        };
+
+        Ok(Mailing {
+            from,
+            reply_to,
+            message_id_domain: front_matter.message_id_domain.or(config.message_id_domain.clone()),
+            headers,
+            subject,
+            body,
+            files,
+            api_endpoint: config.api_endpoint.clone(),
+            verp_address: config.verp_address.clone(),
+            track_opens: config.track_opens,
+            job: None,
+        })
+    }
+
+    /// Uses a VERP return path for the subscriber when one is configured and the
+    /// mailing belongs to a job, otherwise the envelope of the message itself.
+    fn envelope(&self, email: &Message, client: &Subscriber) -> Result<Envelope, String> {
+        let verp: Option<String> = match (&self.verp_address, self.job) {
+            (Some(verp_address), Some(job)) => bounce::encode_verp(verp_address, &client.token, job),
+            _ => None,
+        };
+        match verp {
+            Some(verp) => {
+                let from = match verp.parse() {
+                    Ok(from) => from,
+                    Err(err) => return Err(format!("Invalid return path {}: {}", verp, err)),
+                };
+                match Envelope::new(Some(from), email.envelope().to().to_vec()) {
+                    Ok(envelope) => Ok(envelope),
+                    Err(err) => Err(format!("Error building envelope for {}: {}", client.email, err)),
+                }
+            },
+            None => Ok(email.envelope().clone()),
+        }
+    }
+
+    /// Tags every rendered email with the job it belongs to so bounces can be traced.
+    pub fn set_job(&mut self, job: i64) {
+        self.job = Some(job);
+    }
+
+    /// Builds the message for one subscriber, filling in their placeholders, returning its
+    /// envelope and the raw message including any custom headers.
+    pub fn render(&self, client: &Subscriber) -> Result<(Envelope, Vec<u8>), String> {
+        let values: BTreeMap<String, String> = client.template_values();
+        let to: Mailbox = match client.email.parse() {
+            Ok(to) => to,
+            Err(err) => return Err(format!("Invalid recipient {}: {}", client.email, err)),
+        };
+        let mut builder = Message::builder() 
+            .from(self.from.clone()) 
+            .to(to) 
+            .subject(newsletter::fill(&self.subject, &values, false));
+        if let Some(reply_to) = self.reply_to.clone() {
+            builder = builder.reply_to(reply_to);
+        }
+        if let Some(domain) = &self.message_id_domain {
+            builder = builder.message_id(Some(format!("<{}@{}>", Uuid::new_v4(), domain)));
+        }
+
+        let mut html: String = format!("{}\n<a href=\"{}preferences/{}\">Manage preferences</a> | <a href=\"{}api/remove/{}\">Unsubscribe</a>",
+                                       newsletter::fill(&self.body, &values, true), 
+                                       self.api_endpoint,
+                                       client.token,
+                                       self.api_endpoint,
+                                       client.token);
+        if let (true, Some(job)) = (self.track_opens, self.job) {
+            html.push_str(&format!("\n<img src=\"{}api/open/{}/{}\" width=\"1\" height=\"1\" alt=\"\">",
+                                   self.api_endpoint,
+                                   client.token,
+                                   job));
+        }
+        let email = if self.files.is_empty() {
+            builder
+                .header(ContentType::TEXT_HTML)
+                .body(html)
+        } else {
+            builder
+                .multipart(with_attachments(html, &self.files))
+        };
+
+        match email {
+            Ok(email) => {
+                let mut headers: Headers = self.headers.clone();
+                // lets mail clients offer one-click unsubscribe (RFC 8058)
+                headers.insert_raw(HeaderValue::new(HeaderName::new_from_ascii_str("List-Unsubscribe"),
+                                                    format!("<{}api/remove/{}>", self.api_endpoint, client.token)));
+                headers.insert_raw(HeaderValue::new(HeaderName::new_from_ascii_str("List-Unsubscribe-Post"),
+                                                    String::from("List-Unsubscribe=One-Click")));
+                if let Some(job) = self.job {
+                    headers.insert_raw(HeaderValue::new(HeaderName::new_from_ascii_str(bounce::JOB_HEADER), job.to_string()));
+                }
+                let mut raw: Vec<u8> = headers.to_string().into_bytes();
+                raw.extend(email.formatted());
+                Ok((self.envelope(&email, client)?, raw))
+            },
+            Err(err) => Err(format!("Error building email for {}: {}", client.email, err)),
+        }
+    }
+}
+
+async fn record_delivery(pool: &MySqlPool, job: i64, newsletter: &str, client: &Subscriber, status: &str, error: Option<String>) {
+    if let Err(err) = sqlx::query!(r#"
+                                   INSERT INTO deliveries (job, newsletter, token, email, status, time, error) VALUES (?, ?, ?, ?, ?, ?, ?)"#,
+                                   job,
+                                   newsletter,
+                                   client.token,
+                                   client.email,
+                                   status,
+                                   Utc::now().timestamp(),
+                                   error)
+        .execute(pool)
+        .await {
+            debug!("Error recording delivery to {}: {}", client.email, err);
+        }
+}
+
+/// Sends one message, recording how long the relay took for the metrics.
+fn timed<T>(send: impl FnOnce() -> T) -> T {
+    let started: Instant = Instant::now();
+    let result: T = send();
+    metrics::record_send(started.elapsed());
+    result
+}
+
+/// Hands one message to the relay on the blocking thread pool, so a slow relay doesn't
+/// hold up the rest of the runtime.
+async fn send(mailer: &SmtpTransport, envelope: Envelope, email: Vec<u8>) -> Result<(), String> {
+    let mailer: SmtpTransport = mailer.clone();
+    match tokio::task::spawn_blocking(move || timed(|| mailer.send_raw(&envelope, &email))).await {
+        Ok(Ok(_)) => Ok(()),
+        Ok(Err(err)) => Err(err.to_string()),
+        Err(err) => Err(format!("Sending failed: {}", err)),
+    }
+}
+
+/// Sends a newsletter to every client and records each attempt in the deliveries table,
+/// a dry run renders every message but records it as `dry_run` instead of sending it.
+/// Messages go out at most `send_rate` a second when it's set, dry runs included.
+pub async fn execute_job(pool: &MySqlPool, job: i64, newsletter: String, subject: String, clients: &[Subscriber], dry_run: bool) -> Result<(), String> {
     let config: Config = Config::load_config().unwrap();
     
-    let newsletter_string: String = std::fs::read_to_string(format!("{}{}",config.dir, newsletter.clone()))
-        .expect("Cannot open newsletter to send");
-    let creds = Credentials::new(config.smtp_username, config.smtp_password);
-    let mailer = SmtpTransport::relay(&config.relay) 
-        .unwrap() 
-        .credentials(creds) 
-        .build(); 
+    let mut mailing: Mailing = Mailing::prepare(&newsletter, subject, &config)?;
+    mailing.set_job(job);
+    let mailer: Option<SmtpTransport> = if dry_run { None } else { Some(mailer(&config)) };
+    let mut throttle: Option<Interval> = match config.send_rate {
+        0 => None,
+        rate => {
+            let mut throttle: Interval = interval(Duration::from_secs(1) / rate);
+            throttle.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            Some(throttle)
+        },
+    };
+    let mut sent: usize = 0;
+    let mut failed: usize = 0;
     for client in clients {
-        let email = Message::builder() 
-            .from(config.sender.clone().parse().unwrap()) 
-            .to(client.email.parse().unwrap()) 
-            .subject(subject.clone()) 
-            .header(ContentType::TEXT_HTML)
-            .body(format!("{}\n<a href=\"{}api/remove/{}\">Unsubscribe</a>",
-                          newsletter_string.clone(), 
-                          config.api_endpoint.clone(),
-                          client.token)) 
-            .unwrap(); 
-        match mailer.send(&email) { 
-              Ok(_) => debug!("Email sent successfully!"), 
-              Err(e) => panic!("Could not send email: {:?}", e), 
+        if let Some(throttle) = throttle.as_mut() {
+            throttle.tick().await;
+        }
+        let (envelope, email) = match mailing.render(client) {
+            Ok(rendered) => rendered,
+            Err(err) => {
+                debug!("{}", err);
+                record_delivery(pool, job, &newsletter, client, "failed", Some(err)).await;
+                failed += 1;
+                continue;
             }
+        };
+        match &mailer {
+            Some(mailer) => match send(mailer, envelope, email).await {
+                Ok(_) => {
+                    debug!("Email sent successfully!");
+                    record_delivery(pool, job, &newsletter, client, "sent", None).await;
+                    sent += 1;
+                },
+                Err(e) => {
+                    debug!("Could not send email: {}", e);
+                    record_delivery(pool, job, &newsletter, client, "failed", Some(e)).await;
+                    failed += 1;
+                },
+            },
+            None => {
+                debug!("dry run, would have sent {} bytes to {}", email.len(), client.email);
+                record_delivery(pool, job, &newsletter, client, "dry_run", None).await;
+                sent += 1;
+            },
+        }
+    }
+    debug!("finished {}: {} sent, {} failed{}", newsletter, sent, failed, if dry_run { " (dry run)" } else { "" });
+
+    Ok(())
+}
+
+fn mailer(config: &Config) -> SmtpTransport {
+    let creds = Credentials::new(config.smtp_username.clone(), config.smtp_password.clone());
+    SmtpTransport::relay(&config.relay) 
+        .unwrap() 
+        .credentials(creds) 
+        .build()
+}
+
+/// Sends a one-off email from the configured sender, for confirmations.
+pub async fn send_notice(config: &Config, to: &str, subject: &str, body: String) -> Result<(), String> {
+    let from = match config.sender.parse() {
+        Ok(address) => Mailbox::new(config.sender_name.clone(), address),
+        Err(err) => return Err(format!("Invalid sender {}: {}", config.sender, err)),
+    };
+    let to: Mailbox = match to.parse() {
+        Ok(to) => to,
+        Err(err) => return Err(format!("Invalid email {}: {}", to, err)),
+    };
+    let email: Message = match Message::builder().from(from).to(to).subject(subject).header(ContentType::TEXT_HTML).body(body) {
+        Ok(email) => email,
+        Err(err) => return Err(format!("Error building email: {}", err)),
+    };
+    send(&mailer(config), email.envelope().clone(), email.formatted()).await
+}
+
+/// Connects to the relay and logs in, for the readiness check.
+pub fn check_transport(config: &Config) -> Result<(), String> {
+    match mailer(config).test_connection() {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(format!("Relay {} refused the connection", config.relay)),
+        Err(err) => Err(format!("Cannot connect to relay {}: {}", config.relay, err)),
+    }
+}
+
+/// Looks up a subscriber to render a preview for, falling back to a sample one that
+/// isn't on the list.
+async fn preview_client(config: &Config, email: Option<String>) -> Result<Subscriber, String> {
+    let email: String = match email {
+        Some(email) => email,
+        None => return Ok(Subscriber {
+            token: String::from("preview"),
+            email: String::from("subscriber@example.com"),
+            ..Default::default()
+        }),
+    };
+
+    let pool = MySqlPoolOptions::new()
+        .max_connections(5)
+        .connect(&config.url)
+        .await
+        .expect("Cannot connect to database!");
+
+    match load_subscriber(&pool, &email).await {
+        Ok(Some(client)) => Ok(client),
+        _ => Ok(Subscriber { token: String::from("preview"), email, ..Default::default() }),
+    }
+}
+
+/// Renders a newsletter exactly as execute_job would for one subscriber, returning the
+/// raw message.
+pub async fn preview_job(newsletter: String, subject: Option<String>, subscriber: Option<String>) -> Result<Vec<u8>, String> {
+    let config: Config = Config::load_config()?;
+    let front_matter = newsletter::load(&newsletter)?.front_matter;
+    let subject: String = subject_for(&newsletter, subject, &front_matter)?;
+
+    let client: Subscriber = preview_client(&config, subscriber).await?;
+    let mailing: Mailing = Mailing::prepare(&newsletter, subject, &config)?;
+    let (_, email) = mailing.render(&client)?;
+
+    Ok(email)
+}
+
+/// Sends a newsletter to the given addresses without touching the jobs table.
+pub async fn send_test(newsletter: String, subject: Option<String>, addresses: Vec<String>) -> Result<String, String> {
+    let config: Config = Config::load_config()?;
+    let front_matter = newsletter::load(&newsletter)?.front_matter;
+    let subject: String = subject_for(&newsletter, subject, &front_matter)?;
+
+    let mailing: Mailing = Mailing::prepare(&newsletter, subject, &config)?;
+    let mailer: SmtpTransport = mailer(&config);
+    for address in &addresses {
+        let client: Subscriber = preview_client(&config, Some(address.clone())).await?;
+        let (envelope, email) = mailing.render(&client)?;
+        if let Err(err) = send(&mailer, envelope, email).await {
+            return Err(format!("Could not send test email to {}: {}", address, err));
+        }
+        debug!("sent test email to {}", address);
     }
 
+    Ok(format!("Sent {} to {} test addresses", newsletter, addresses.len()))
+}
+
+/// Wraps the html body with its inline images in a related part, followed by the
+/// regular attachments.
+fn with_attachments(html: String, files: &[(newsletter::Attachment, Vec<u8>, ContentType)]) -> MultiPart {
+    let mut related: MultiPart = MultiPart::related().singlepart(SinglePart::html(html));
+    for (attachment, content, content_type) in files {
+        if let Some(content_id) = &attachment.content_id {
+            related = related.singlepart(Attachment::new_inline(content_id.clone())
+                                         .body(content.clone(), content_type.clone()));
+        }
+    }
+
+    let mut mixed: MultiPart = MultiPart::mixed().multipart(related);
+    for (attachment, content, content_type) in files {
+        if attachment.content_id.is_none() {
+            let filename: String = std::path::Path::new(&attachment.path)
+                .file_name()
+                .map(|name| name.to_string_lossy().to_string())
+                .unwrap_or(attachment.path.clone());
+            mixed = mixed.singlepart(Attachment::new(filename)
+                                     .body(content.clone(), content_type.clone()));
+        }
+    }
+
+    mixed
+}
+
+/// Sends a job that's due and archives it. A daemon started with --dry-run leaves real
+/// jobs in place so they're still sent once a real daemon runs.
+async fn run_job(pool: &MySqlPool, newsletter: &Job, dry_run: bool) -> Result<(), String> {
+    let dry_run: bool = dry_run || newsletter.dry_run;
+    let clients: Vec<Subscriber> = get_recipients(pool, &newsletter.list, &newsletter.segment).await?;
+    execute_job(pool, newsletter.id, newsletter.newsletter.clone(), newsletter.subject.clone(), &clients, dry_run).await?;
+    if newsletter.archive && !dry_run {
+        if let Err(err) = archive::publish(pool, newsletter).await {
+            error!("Error archiving {}: {}", newsletter.newsletter, err);
+        }
+    }
+    if dry_run && !newsletter.dry_run {
+        return Ok(());
+    }
+    remove_job(newsletter.newsletter.clone()).await?;
     Ok(())
 }
 
-pub async fn execute_daemon(){
-    tokio::spawn(async {
+pub async fn execute_daemon(dry_run: bool){
+    tokio::spawn(async move {
         let config: Config = Config::load_config().unwrap();
 
         let pool = MySqlPoolOptions::new()
@@ -119,56 +625,158 @@ pub async fn execute_daemon(){
             .await
             .expect("Cannot connect to database!");
         debug!("spawned server");
-        let mut interval = interval(Duration::from_secs(config.interval.clone() * 60));
+        let mut interval = interval(Duration::from_secs(config.interval * 60));
         interval.tick().await; // first tick fires immediately, ignore it
+        // real jobs a dry run daemon has already run, so they aren't run every interval
+        let mut dry_runs: HashSet<i64> = HashSet::new();
         loop {
 
-            let jobs_list: Result<Vec<Job>, sqlx::Error> = sqlx::query_as!(Job,"SELECT * FROM jobs")
-                .fetch_all(&pool)
-                .await;
+            let jobs_list: Result<Vec<Job>, sqlx::Error> = load_jobs(&pool).await;
 
-            let clients: Vec<MailingList> = sqlx::query_as!(MailingList, "SELECT * from mailing_list")
-                .fetch_all(&pool)
-                .await
-                .unwrap();
             debug!("checking jobs");
 
             match jobs_list{
                     Ok(jobs) => {
                         for newsletter in jobs {
-                            if compare_time(newsletter.time){
+                            if compare_time(newsletter.time) && !dry_runs.contains(&newsletter.id) {
                                 debug!("executing job: {}", newsletter.newsletter.clone());
-                                execute_job(newsletter.newsletter.clone(), newsletter.subject.clone(), &clients).unwrap();
-                                remove_job(newsletter.newsletter).await.unwrap();
+                                // a job that fails is left in place and tried again next interval
+                                match run_job(&pool, &newsletter, dry_run).await {
+                                    Ok(_) if dry_run => {
+                                        dry_runs.insert(newsletter.id);
+                                    },
+                                    Ok(_) => (),
+                                    Err(err) => error!("Error running the job for {}: {}", newsletter.newsletter, err),
+                                }
                             }
                         };
                     },
                     Err(err) => debug!("Error getting jobs from database: {}", err)
                 }
+            if config.bounce_maildir.is_some() || config.bounce_mbox.is_some() {
+                match bounce::process_bounces().await {
+                    Ok(output) => debug!("{}", output),
+                    Err(err) => debug!("Error processing bounces: {}", err)
+                }
+            }
             debug!("waiting {} minutes to check jobs again", config.interval);
             interval.tick().await;
             }
     }).await.unwrap();
 }
 
+/// Everyone on the list, or the whole mailing list, minus anyone on the suppression list,
+/// anyone sent a newsletter more recently than their chosen frequency allows and anyone
+/// outside the job's segment.
+async fn get_recipients(pool: &MySqlPool, list: &Option<String>, segment: &Option<String>) -> Result<Vec<Subscriber>, String> {
+    let recipients: Result<Vec<MailingList>, sqlx::Error> = match list {
+        Some(list) => {
+            sqlx::query_as!(MailingList, r#"
+                            SELECT mailing_list.token, mailing_list.email FROM mailing_list
+                            JOIN list_members ON list_members.token = mailing_list.token
+                            WHERE list_members.list = (?)
+                            AND NOT EXISTS (SELECT hash FROM suppressions WHERE hash = SHA2(LOWER(mailing_list.email), 256))"#,
+                            list)
+                .fetch_all(pool)
+                .await
+        },
+        None => {
+            sqlx::query_as!(MailingList, r#"
+                            SELECT token, email from mailing_list
+                            WHERE NOT EXISTS (SELECT hash FROM suppressions WHERE hash = SHA2(LOWER(mailing_list.email), 256))"#)
+                .fetch_all(pool)
+                .await
+        }
+    };
+    let recipients: HashSet<String> = match recipients {
+        Ok(recipients) => recipients.into_iter().map(|recipient| recipient.token).collect(),
+        Err(err) => return Err(format!("Error getting recipients: {}", err)),
+    };
+
+    // load their names, tags and fields for filling in the newsletter
+    let mut subscribers: Vec<Subscriber> = load_subscribers(pool).await?;
+    subscribers.retain(|subscriber| recipients.contains(&subscriber.token));
+    let activity: HashMap<String, Activity> = segment::load_activity(pool).await?;
+    let now: i64 = Utc::now().timestamp();
+    subscribers.retain(|subscriber| {
+        preferences::allows(&subscriber.frequency, activity.get(&subscriber.token).and_then(|activity| activity.sent), now)
+    });
+    match segment {
+        Some(segment) => segment::select(pool, &Segment::parse(segment)?, subscribers).await,
+        None => Ok(subscribers),
+    }
+}
+
 fn compare_time(time: i64) -> bool{
     let start_time = Utc::now().timestamp();
-    debug!("{}", &time - &start_time);
-    if time - start_time < 0 {
-        true
-    } else {
-        false
-    } 
+    debug!("{}", time - start_time);
+    time - start_time < 0
 }
 
 #[cfg(test)]
 mod tests {
     use lettre::transport::smtp::authentication::Credentials; 
     use lettre::{SmtpTransport, Transport};
-    use lettre::message::{header::ContentType, Message};
+    use lettre::message::{header::{ContentType, HeaderName, HeaderValue, Headers}, Message};
     use tokio::time::{interval, Duration};
 
     use crate::config::Config;
+    use crate::subscribers::Subscriber;
+    use super::{tally, JobStats, Mailing};
+
+    #[test]
+    fn render_sender_and_headers() {
+        let mut headers = Headers::new();
+        headers.insert_raw(HeaderValue::new(HeaderName::new_from_ascii(String::from("Precedence")).unwrap(), String::from("bulk")));
+        let mailing = Mailing {
+            from: "Newsman <newsletter@example.com>".parse().unwrap(),
+            reply_to: Some("editor@example.com".parse().unwrap()),
+            message_id_domain: Some(String::from("example.com")),
+            headers,
+            subject: String::from("Newsletter for {{ email }}"),
+            body: String::from("<p>Hi {{ name | there }}, {{ plan }} plan</p>"),
+            files: Vec::new(),
+            api_endpoint: String::from("http://127.0.0.1:3600/"),
+            verp_address: Some(String::from("bounces@example.com")),
+            track_opens: true,
+            job: Some(42),
+        };
+        let client = Subscriber {
+            token: String::from("1"),
+            email: String::from("example@test.com"),
+            fields: [(String::from("plan"), String::from("pro"))].into(),
+            ..Default::default()
+        };
+
+        let (envelope, raw) = mailing.render(&client).unwrap();
+        let raw = String::from_utf8(raw).unwrap();
+
+        assert_eq!("bounces+1-42@example.com", envelope.from().unwrap().to_string());
+        assert!(raw.starts_with("Precedence: bulk\r\nList-Unsubscribe: <http://127.0.0.1:3600/api/remove/1>\r\nList-Unsubscribe-Post: List-Unsubscribe=One-Click\r\nX-Newsman-Job: 42\r\n"));
+        assert!(raw.contains("From: Newsman <newsletter@example.com>\r\n"));
+        assert!(raw.contains("Reply-To: editor@example.com\r\n"));
+        assert!(raw.contains("@example.com>\r\n"));
+        assert!(raw.contains("api/remove/1"));
+        assert!(raw.contains("preferences/1"));
+        assert!(raw.contains("Subject: Newsletter for example@test.com\r\n"));
+        assert!(raw.contains("<p>Hi there, pro plan</p>"));
+        assert!(raw.contains("api/open/1/42"));
+    }
+
+    #[test]
+    fn tally_job_stats() {
+        let deliveries = vec![
+            (1, String::from("october"), String::from("sent"), 10),
+            (1, String::from("october"), String::from("failed"), 2),
+            (2, String::from("november"), String::from("dry_run"), 5),
+        ];
+
+        let stats = tally(deliveries, vec![(1, 3), (7, 1)], vec![(1, 4)]);
+        assert_eq!(vec![
+            JobStats { job: 1, newsletter: String::from("october"), sent: 10, failed: 2, dry_run: 0, bounced: 3, opened: 4 },
+            JobStats { job: 2, newsletter: String::from("november"), dry_run: 5, ..Default::default() },
+        ], stats);
+    }
 
     #[tokio::test]
     async fn new_job() {
@@ -189,7 +797,7 @@ mod tests {
             .unwrap(); 
 
         match mailer.send(&email) { 
-              Ok(_) => assert!(true), 
+              Ok(_) => (), 
               Err(e) => panic!("Could not send email: {:?}", e), 
             }
     }
@@ -199,7 +807,6 @@ mod tests {
         tokio::spawn(async {
             let mut interval = interval(Duration::from_secs(1));
             interval.tick().await; // first tick fires immediately, ignore it
-            assert!(true)
         });
     }
 }
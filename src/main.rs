@@ -1,20 +1,34 @@
+pub mod admin;
+pub mod archive;
+pub mod bounce;
 pub mod config;
 pub mod emails;
+pub mod feed;
+pub mod gdpr;
+pub mod import;
 pub mod job;
+pub mod metrics;
+pub mod newsletter;
+pub mod preferences;
+pub mod segment;
 pub mod server;
+pub mod spam;
+pub mod subscribers;
+pub mod suppression;
+pub mod widget;
 
 extern crate daemonize;
 
 use chrono::Utc;
 use daemonize::Daemonize;
+use std::collections::BTreeMap;
 use std::fs::{File, create_dir};
+use std::io::Write;
 use std::path::Path;
 use env_logger::Builder;
 use clap::Parser;
 use log::{debug, LevelFilter};
 
-use warp;
-
 use crate::config::Config;
 
 
@@ -25,10 +39,66 @@ struct Args {
     #[arg(short, value_name = "EMAIL")]
     add_email: Option<String>,
    
+    /// List to add an email to, used with -a, or to filter --subscribers and --export, -l [list]
+    #[arg(short, value_name = "LIST")]
+    list: Option<String>,
+
+    /// Name of the subscriber, used with -a
+    #[arg(long, value_name = "NAME")]
+    name: Option<String>,
+
+    /// Tags to give the subscriber with -a, or only list and export subscribers with these tags
+    #[arg(long, value_name = "TAGS", value_delimiter = ',')]
+    tag: Vec<String>,
+
+    /// Sets a custom field on the subscriber with -a, can be given more than once
+    #[arg(long, value_name = "NAME=VALUE")]
+    field: Vec<String>,
+
+    /// Imports subscribers from a .csv or .json file, -i [file]
+    #[arg(short, value_name = "FILE")]
+    import: Option<String>,
+
+    /// Lists subscribers, filtered with -l, --status, --tag and --since
+    #[arg(long)]
+    subscribers: bool,
+
+    /// Exports subscribers to a .csv or .json file, filtered the same as --subscribers
+    #[arg(long, value_name = "FILE")]
+    export: Option<String>,
+
+    /// Only lists or exports subscribers with this status
+    #[arg(long, value_name = "STATUS")]
+    status: Option<String>,
+
+    /// Only lists or exports subscribers who signed up on or after this date, YYYY-MM-DD
+    #[arg(long, value_name = "DATE")]
+    since: Option<String>,
+
+    /// Prints everything stored about an email as JSON, or writes it to the file given with -o
+    #[arg(long, value_name = "EMAIL")]
+    gdpr_export: Option<String>,
+
+    /// Erases everything stored about an email, leaving a hash on the suppression list
+    #[arg(long, value_name = "EMAIL")]
+    gdpr_erase: Option<String>,
+
     /// Remove email from mailing list, -r [email]
     #[arg(short, value_name = "EMAIL")]
     remove_email: Option<String>,
 
+    /// Adds an email to the suppression list so it can't be added or mailed
+    #[arg(long, value_name = "EMAIL")]
+    block: Option<String>,
+
+    /// Removes an email from the suppression list
+    #[arg(long, value_name = "EMAIL")]
+    unblock: Option<String>,
+
+    /// Reads bounces from the configured maildir or mbox and suppresses bouncing emails
+    #[arg(long)]
+    process_bounces: bool,
+
     /// Starts a mailing job, -j [newsletter name]
     #[arg(short, value_name = "NEWSLETTER NAME")]
     job: Option<String>,
@@ -37,14 +107,43 @@ struct Args {
     #[arg(short, value_name = "NEWSLETTER NAME")]
     unassign_job: Option<String>,
 
-    /// Time for job to be started defaults to 0 Minutes, -t [delay for job]
+    /// Time for job to be started, defaults to the newsletter's send_at or 0 Minutes, -t [delay for job]
     #[arg(short, value_name = "TIME")]
     time: Option<i64>,
 
-    /// Subject line for a mailing job, defaults to newsletter, -s [subject]
+    /// Subject line for a mailing job, defaults to the newsletter's front matter, -s [subject]
     #[arg(short, value_name = "SUBJECT")]
     subject: Option<String>,
 
+    /// Previews a newsletter as a subscriber would receive it, -p [newsletter name]
+    #[arg(short, value_name = "NEWSLETTER NAME")]
+    preview: Option<String>,
+
+    /// Subscriber to render the preview for, defaults to a sample subscriber
+    #[arg(long, value_name = "EMAIL")]
+    subscriber: Option<String>,
+
+    /// Writes the preview to an .eml file instead of stdout, -o [file]
+    #[arg(short, value_name = "FILE")]
+    output: Option<String>,
+
+    /// Sends the preview to these comma separated addresses instead of showing it
+    #[arg(long, value_name = "EMAILS", value_delimiter = ',')]
+    send_test: Option<Vec<String>>,
+
+    /// Only sends the job to subscribers matching this filter, or shows who matches it
+    /// when given without -j, --segment "tag:beta AND NOT opened_last:90d"
+    #[arg(long, value_name = "EXPRESSION")]
+    segment: Option<String>,
+
+    /// Runs jobs without sending any mail, recording what would have been sent, used with -j or -e
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Leaves the job out of the public archive once it's sent, used with -j
+    #[arg(long)]
+    no_archive: bool,
+
     /// Starts a tokio server that automatically does jobs when the time comes, -e
     #[arg(short)]
     execute: Option<bool>,
@@ -65,21 +164,79 @@ struct Args {
 async fn parse_cli(cli: Args) -> anyhow::Result<()> {
     std::env::set_var("RUST_LOG", "debug");
 
-    let delay: i64;
+    let delay: Option<i64> = cli.time.map(|time| {
+        debug!("{}", &time);
+        Utc::now().timestamp() + (time * 60)
+    });
 
-    match cli.time {
-        Some(time) => {
-            debug!("{}", &time);
-            delay = Utc::now().timestamp() + (time * 60);
-        },
-        None => {
-            delay = Utc::now().timestamp();
+    if let Some(email) = cli.add_email.as_deref() {
+        debug!("{}", email);
+        let fields: Result<BTreeMap<String, String>, String> = cli.field.iter()
+            .map(|field| match field.split_once('=') {
+                Some((name, value)) => Ok((name.trim().to_string(), value.to_string())),
+                None => Err(format!("Invalid field {}, expected NAME=VALUE", field)),
+            })
+            .collect();
+        let output: Result<String, String> = match fields {
+            Ok(fields) => emails::add_email(emails::NewSubscriber {
+                email: email.to_string(),
+                name: cli.name.clone(),
+                lists: cli.list.clone().into_iter().collect(),
+                tags: cli.tag.clone(),
+                fields,
+            }).await,
+            Err(err) => Err(err),
+        };
+
+        match output {
+            Ok(output) => println!("{}", output),
+            Err(err) => println!("{}", err)
         }
     }
 
-    if let Some(email) = cli.add_email.as_deref() {
-        debug!("{}", email);
-        let output: Result<String, String> = emails::add_email(email.to_string())
+    if let Some(path) = cli.import.as_deref() {
+        debug!("importing {}", path);
+        let output: Result<String, String> = import::import_subscribers(path.to_string())
+            .await;
+
+        match output {
+            Ok(output) => println!("{}", output),
+            Err(err) => println!("{}", err)
+        }
+    }
+
+    if cli.subscribers || cli.export.is_some() {
+        let output: Result<String, String> = match subscribers::Filter::new(cli.list.clone(), cli.status.clone(), cli.tag.clone(), cli.since.clone()) {
+            Ok(filter) => match cli.export.as_deref() {
+                Some(path) => {
+                    debug!("exporting subscribers to {}", path);
+                    subscribers::export_subscribers(path.to_string(), filter).await
+                },
+                None => subscribers::list_subscribers(filter).await,
+            },
+            Err(err) => Err(err),
+        };
+
+        match output {
+            Ok(output) => println!("{}", output),
+            Err(err) => println!("{}", err)
+        }
+    }
+
+    if let Some(email) = cli.gdpr_export.as_deref() {
+        debug!("exporting data stored about {}", email);
+        let output: Result<String, String> = gdpr::export_data(email.to_string(), cli.output.clone())
+            .await;
+
+        match output {
+            Ok(output) => println!("{}", output),
+            Err(err) => println!("{}", err)
+        }
+    }
+
+    if let Some(email) = cli.gdpr_erase.as_deref() {
+        debug!("erasing {}", email);
+        let output: Result<String, String> = gdpr::erase(email.to_string())
             .await;
 
         match output {
@@ -99,25 +256,90 @@ async fn parse_cli(cli: Args) -> anyhow::Result<()> {
         }
     }
 
-    if let Some(true) = cli.execute {
-        debug!("executing job server");
-        job::execute_daemon()
+    if let Some(email) = cli.block.as_deref() {
+        debug!("blocking {}", email);
+        let output: Result<String, String> = suppression::block_email(email.to_string())
             .await;
+
+        match output {
+            Ok(output) => println!("{}", output),
+            Err(err) => println!("{}", err)
+        }
     }
 
-    if let Some(job) = cli.job.as_deref() {
-        debug!("Assigning job {:?}s", &job);
-        if let Some(subject) = cli.subject.as_deref() {
-            debug!("With the subject {:?}s", &subject);
-            let output: Result<String, String> = job::add_job(job.to_string(), delay, subject.to_string())
+    if let Some(email) = cli.unblock.as_deref() {
+        debug!("unblocking {}", email);
+        let output: Result<String, String> = suppression::unblock_email(email.to_string())
+            .await;
+
+        match output {
+            Ok(output) => println!("{}", output),
+            Err(err) => println!("{}", err)
+        }
+    }
+
+    if cli.process_bounces {
+        debug!("processing bounces");
+        let output: Result<String, String> = bounce::process_bounces()
+            .await;
+
+        match output {
+            Ok(output) => println!("{}", output),
+            Err(err) => println!("{}", err)
+        }
+    }
+
+    if let Some(newsletter) = cli.preview.as_deref() {
+        debug!("Previewing {}", &newsletter);
+        if let Some(addresses) = cli.send_test.clone() {
+            let output: Result<String, String> = job::send_test(newsletter.to_string(), cli.subject.clone(), addresses)
                 .await;
 
             match output {
                 Ok(output) => println!("{}", output),
                 Err(err) => println!("{}", err)
             }
+        } else {
+            let output: Result<Vec<u8>, String> = job::preview_job(newsletter.to_string(), cli.subject.clone(), cli.subscriber.clone())
+                .await;
+
+            match output {
+                Ok(email) => match cli.output.as_deref() {
+                    Some(file) => std::fs::write(file, email)?,
+                    None => std::io::stdout().write_all(&email)?,
+                },
+                Err(err) => println!("{}", err)
+            }
         }
+    }
 
+    if let Some(true) = cli.execute {
+        debug!("executing job server");
+        job::execute_daemon(cli.dry_run)
+            .await;
+    }
+
+    if let Some(job) = cli.job.as_deref() {
+        debug!("Assigning job {:?}s", &job);
+        debug!("With the subject {:?}s", &cli.subject);
+        let output: Result<String, String> = job::add_job(job.to_string(), delay, cli.subject.clone(), cli.segment.clone(), cli.dry_run, cli.no_archive.then_some(false))
+            .await;
+
+        match output {
+            Ok(output) => println!("{}", output),
+            Err(err) => println!("{}", err)
+        }
+    }
+
+    if let (Some(segment), None) = (cli.segment.as_deref(), cli.job.as_deref()) {
+        debug!("previewing segment {}", segment);
+        let output: Result<String, String> = segment::preview_segment(segment.to_string())
+            .await;
+
+        match output {
+            Ok(output) => println!("{}", output),
+            Err(err) => println!("{}", err)
+        }
     }
 
     if let Some(unassign_job) = cli.unassign_job.as_deref() {
@@ -153,9 +375,10 @@ pub async fn main() -> anyhow::Result<()>{
     if let Some(true) = cli.warp {
         debug!("spawning warp server...");
         tokio::spawn(async {
-            warp::serve(server::construct_route())
-                .run(([127, 0, 0, 1], 3600))
-                .await;
+            let config: Config = Config::load_config().unwrap();
+            if let Err(err) = server::serve(&config).await {
+                println!("{}", err);
+            }
         });
         debug!("Warp server running in a thread...");
     }
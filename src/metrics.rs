@@ -0,0 +1,348 @@
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use serde::Serialize;
+use sqlx::Connection;
+use sqlx::mysql::{MySqlPool, MySqlPoolOptions};
+use warp::{Filter, http::StatusCode};
+use log::{debug, warn};
+
+use crate::Config;
+use crate::job;
+use crate::suppression;
+use crate::server::bearer_authorised;
+use crate::subscribers::{self, ListSize};
+
+/// Upper bounds of the send latency histogram buckets, in seconds.
+const LATENCY_BUCKETS: [f64; 8] = [0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+/// Routes requests are counted under, anything else is counted as `other` so unknown
+/// paths can't add labels.
+const ROUTES: [&str; 18] = ["/api/add", "/api/challenge", "/api/form-token", "/api/gdpr", "/api/jobs",
+                            "/api/lists", "/api/open", "/api/remove", "/api/resubscribe", "/api/stats",
+                            "/api/subscribers", "/api/webhook", "/archive", "/embed", "/preferences", "/healthz", "/readyz", "/metrics"];
+
+#[derive(Debug, Clone, PartialEq)]
+struct Histogram {
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    const fn new() -> Histogram {
+        Histogram { buckets: [0; LATENCY_BUCKETS.len()], count: 0, sum: 0.0 }
+    }
+
+    fn observe(&mut self, seconds: f64) {
+        for (bucket, bound) in self.buckets.iter_mut().zip(LATENCY_BUCKETS) {
+            if seconds <= bound {
+                *bucket += 1;
+            }
+        }
+        self.count += 1;
+        self.sum += seconds;
+    }
+}
+
+/// Everything counted by this process since it started.
+#[derive(Debug, Clone, PartialEq)]
+struct Counters {
+    requests: BTreeMap<(String, &'static str, u16), u64>,
+    signups: BTreeMap<&'static str, u64>,
+    send_latency: Histogram,
+}
+
+impl Counters {
+    const fn new() -> Counters {
+        Counters { requests: BTreeMap::new(), signups: BTreeMap::new(), send_latency: Histogram::new() }
+    }
+}
+
+static COUNTERS: Mutex<Counters> = Mutex::new(Counters::new());
+
+fn route_label(path: &str) -> &'static str {
+    ROUTES.iter()
+        .find(|route| path == **route || path.strip_prefix(**route).is_some_and(|rest| rest.starts_with('/')))
+        .copied()
+        .unwrap_or("other")
+}
+
+/// Counts a request the warp server answered, used with `warp::log::custom`.
+pub fn record_request(info: warp::log::Info) {
+    let key = (info.method().to_string(), route_label(info.path()), info.status().as_u16());
+    *COUNTERS.lock().unwrap().requests.entry(key).or_insert(0) += 1;
+}
+
+/// Counts a signup by its result, such as subscribed or rate_limited.
+pub fn record_signup(result: &'static str) {
+    *COUNTERS.lock().unwrap().signups.entry(result).or_insert(0) += 1;
+}
+
+/// Records how long the relay took to accept a message.
+pub fn record_send(took: Duration) {
+    COUNTERS.lock().unwrap().send_latency.observe(took.as_secs_f64());
+}
+
+/// The counts read from the database for each scrape.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Totals {
+    pub subscribers: Vec<(String, i64)>,
+    pub lists: Vec<ListSize>,
+    pub jobs_pending: i64,
+    pub messages: Vec<(String, i64)>,
+    pub unsubscribes: Vec<(String, i64)>,
+}
+
+async fn totals(pool: &MySqlPool) -> Result<Totals, String> {
+    let subscribers: Vec<(String, i64)> = match sqlx::query!(r#"
+                                                            SELECT status, COUNT(*) AS count FROM mailing_list GROUP BY status ORDER BY status"#)
+        .fetch_all(pool)
+        .await {
+            Ok(records) => records.into_iter().map(|record| (record.status, record.count)).collect(),
+            Err(err) => return Err(format!("Error counting subscribers: {}", err)),
+        };
+
+    let jobs_pending: i64 = match sqlx::query!(r#"
+                                              SELECT COUNT(*) AS count FROM jobs"#)
+        .fetch_one(pool)
+        .await {
+            Ok(record) => record.count,
+            Err(err) => return Err(format!("Error counting jobs: {}", err)),
+        };
+
+    let messages: Vec<(String, i64)> = match sqlx::query!(r#"
+                                                         SELECT status, COUNT(*) AS count FROM deliveries GROUP BY status ORDER BY status"#)
+        .fetch_all(pool)
+        .await {
+            Ok(records) => records.into_iter().map(|record| (record.status, record.count)).collect(),
+            Err(err) => return Err(format!("Error counting deliveries: {}", err)),
+        };
+
+    // read from the suppression list so unsubscribes by the CLI, the admin API and
+    // bounces are counted whichever process handled them
+    let unsubscribes: Vec<(String, i64)> = match sqlx::query!(r#"
+                                                             SELECT reason, COUNT(*) AS count FROM suppressions WHERE reason IN (?, ?, ?) GROUP BY reason ORDER BY reason"#,
+                                                             suppression::UNSUBSCRIBED,
+                                                             suppression::BOUNCED,
+                                                             suppression::COMPLAINED)
+        .fetch_all(pool)
+        .await {
+            Ok(records) => records.into_iter().map(|record| (record.reason, record.count)).collect(),
+            Err(err) => return Err(format!("Error counting unsubscribes: {}", err)),
+        };
+
+    Ok(Totals { subscribers, lists: subscribers::list_sizes(pool).await?, jobs_pending, messages, unsubscribes })
+}
+
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+fn metric(output: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(output, "# HELP {} {}\n# TYPE {} {}", name, help, name, kind);
+}
+
+/// The metrics in the Prometheus text format.
+fn render(totals: &Totals, counters: &Counters) -> String {
+    let mut output: String = String::new();
+
+    metric(&mut output, "newsman_subscribers", "gauge", "Subscribers on the mailing list by status.");
+    for (status, count) in &totals.subscribers {
+        let _ = writeln!(output, "newsman_subscribers{{status=\"{}\"}} {}", escape_label(status), count);
+    }
+    metric(&mut output, "newsman_list_members", "gauge", "Subscribers on each list.");
+    for list in &totals.lists {
+        let _ = writeln!(output, "newsman_list_members{{list=\"{}\"}} {}", escape_label(&list.list), list.members);
+    }
+    metric(&mut output, "newsman_jobs_pending", "gauge", "Jobs waiting to be sent.");
+    let _ = writeln!(output, "newsman_jobs_pending {}", totals.jobs_pending);
+    metric(&mut output, "newsman_messages_total", "counter", "Messages recorded in the deliveries table by status.");
+    for (status, count) in &totals.messages {
+        let _ = writeln!(output, "newsman_messages_total{{status=\"{}\"}} {}", escape_label(status), count);
+    }
+
+    metric(&mut output, "newsman_signups_total", "counter", "Signups handled by this process by result.");
+    for (result, count) in &counters.signups {
+        let _ = writeln!(output, "newsman_signups_total{{result=\"{}\"}} {}", result, count);
+    }
+    metric(&mut output, "newsman_unsubscribed", "gauge", "Emails on the suppression list for unsubscribing, bouncing or complaining.");
+    for (reason, count) in &totals.unsubscribes {
+        let _ = writeln!(output, "newsman_unsubscribed{{reason=\"{}\"}} {}", escape_label(reason), count);
+    }
+
+    metric(&mut output, "newsman_send_duration_seconds", "histogram", "Time the relay took to accept each message.");
+    for (count, bound) in counters.send_latency.buckets.iter().zip(LATENCY_BUCKETS) {
+        let _ = writeln!(output, "newsman_send_duration_seconds_bucket{{le=\"{}\"}} {}", bound, count);
+    }
+    let latency: &Histogram = &counters.send_latency;
+    let _ = writeln!(output, "newsman_send_duration_seconds_bucket{{le=\"+Inf\"}} {}", latency.count);
+    let _ = writeln!(output, "newsman_send_duration_seconds_sum {}", latency.sum);
+    let _ = writeln!(output, "newsman_send_duration_seconds_count {}", latency.count);
+
+    metric(&mut output, "newsman_http_requests_total", "counter", "Requests answered by the warp server.");
+    for ((method, route, status), count) in &counters.requests {
+        let _ = writeln!(output, "newsman_http_requests_total{{method=\"{}\",route=\"{}\",status=\"{}\"}} {}", method, route, status, count);
+    }
+
+    output
+}
+
+async fn check_database(config: &Config) -> Result<(), String> {
+    let pool: MySqlPool = MySqlPoolOptions::new()
+        .max_connections(1)
+        .acquire_timeout(Duration::from_secs(5))
+        .connect(&config.url)
+        .await
+        .map_err(|err| format!("Cannot connect to database: {}", err))?;
+    let mut connection = pool.acquire().await.map_err(|err| format!("Cannot connect to database: {}", err))?;
+    connection.ping().await.map_err(|err| format!("Database isn't answering: {}", err))
+}
+
+async fn check_transport(config: &Config) -> Result<(), String> {
+    let config: Config = config.clone();
+    match tokio::task::spawn_blocking(move || job::check_transport(&config)).await {
+        Ok(result) => result,
+        Err(err) => Err(format!("Transport check failed: {}", err)),
+    }
+}
+
+/// How long a readiness check is reused for, so /readyz can't be used to make the
+/// daemon log in to the relay over and over.
+const READINESS_CACHE: Duration = Duration::from_secs(30);
+
+/// Whether each readiness check passed, `ok` or `fail`. Why a check failed is only logged.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Readiness {
+    pub database: &'static str,
+    pub transport: &'static str,
+}
+
+impl Readiness {
+    fn is_ready(&self) -> bool {
+        self.database == "ok" && self.transport == "ok"
+    }
+}
+
+static READINESS: Mutex<Option<(Instant, Readiness)>> = Mutex::new(None);
+
+fn outcome(check: &str, result: Result<(), String>) -> &'static str {
+    match result {
+        Ok(_) => "ok",
+        Err(err) => {
+            warn!("{} readiness check failed: {}", check, err);
+            "fail"
+        },
+    }
+}
+
+async fn check_readiness() -> Readiness {
+    if let Some((checked, readiness)) = READINESS.lock().unwrap().as_ref() {
+        if checked.elapsed() < READINESS_CACHE {
+            return readiness.clone();
+        }
+    }
+    let config: Config = Config::load_config().unwrap();
+    let (database, transport) = tokio::join!(check_database(&config), check_transport(&config));
+    let readiness = Readiness { database: outcome("database", database), transport: outcome("transport", transport) };
+    *READINESS.lock().unwrap() = Some((Instant::now(), readiness.clone()));
+    readiness
+}
+
+/// The daemon is up as long as it answers.
+pub async fn handle_healthz() -> Result<impl warp::Reply, Infallible> {
+    Ok("ok")
+}
+
+/// Ready when the database and the mail relay can both be reached, checked at most
+/// every READINESS_CACHE.
+pub async fn handle_readyz() -> Result<impl warp::Reply, Infallible> {
+    let readiness: Readiness = check_readiness().await;
+    let status: StatusCode = if readiness.is_ready() { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+    debug!("readiness: {:?}", readiness);
+    Ok(warp::reply::with_status(warp::reply::json(&readiness), status))
+}
+
+/// Needs a bearer token from `api_tokens`, the same as the admin API.
+pub async fn handle_metrics(authorization: Option<String>) -> Result<Box<dyn warp::Reply>, Infallible> {
+    let config: Config = Config::load_config().unwrap();
+    if !bearer_authorised(authorization.as_deref(), &config.api_tokens) {
+        return Ok(Box::new(warp::reply::with_status("Invalid API token", StatusCode::UNAUTHORIZED)));
+    }
+
+    let pool = MySqlPoolOptions::new()
+        .max_connections(5)
+        .connect(&config.url)
+        .await
+        .expect("Cannot connect to database!");
+
+    match totals(&pool).await {
+        Ok(totals) => {
+            let counters: Counters = COUNTERS.lock().unwrap().clone();
+            Ok(Box::new(warp::reply::with_header(render(&totals, &counters), "content-type", "text/plain; version=0.0.4")))
+        },
+        Err(err) => {
+            debug!("{}", err);
+            Ok(Box::new(warp::reply::with_status(err, StatusCode::INTERNAL_SERVER_ERROR)))
+        },
+    }
+}
+
+pub fn metrics_route() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone{
+    let healthz = warp::path!("healthz")
+        .and(warp::get())
+        .and_then(handle_healthz);
+    let readyz = warp::path!("readyz")
+        .and(warp::get())
+        .and_then(handle_readyz);
+    let metrics = warp::path!("metrics")
+        .and(warp::get())
+        .and(warp::header::optional::<String>("authorization"))
+        .and_then(handle_metrics);
+
+    healthz.or(readyz).or(metrics)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn label_routes() {
+        assert_eq!("/api/remove", route_label("/api/remove/abc-123"));
+        assert_eq!("/api/add", route_label("/api/add"));
+        assert_eq!("/embed", route_label("/embed/signup.js"));
+        assert_eq!("other", route_label("/api/address"));
+        assert_eq!("other", route_label("/wp-login.php"));
+    }
+
+    #[test]
+    fn render_metrics() {
+        let totals = Totals {
+            subscribers: vec![(String::from("subscribed"), 3)],
+            lists: vec![ListSize { list: String::from("weekly"), members: 2 }],
+            jobs_pending: 1,
+            messages: vec![(String::from("failed"), 1), (String::from("sent"), 5)],
+            unsubscribes: vec![(String::from("bounced"), 1), (String::from("unsubscribed"), 4)],
+        };
+        let mut counters = Counters::new();
+        counters.signups.insert("subscribed", 2);
+        counters.requests.insert((String::from("POST"), "/api/add", 201), 2);
+        counters.send_latency.observe(0.2);
+        counters.send_latency.observe(3.0);
+
+        let output = render(&totals, &counters);
+        assert!(output.contains("newsman_subscribers{status=\"subscribed\"} 3\n"));
+        assert!(output.contains("newsman_list_members{list=\"weekly\"} 2\n"));
+        assert!(output.contains("newsman_jobs_pending 1\n"));
+        assert!(output.contains("newsman_messages_total{status=\"sent\"} 5\n"));
+        assert!(output.contains("newsman_signups_total{result=\"subscribed\"} 2\n"));
+        assert!(output.contains("newsman_unsubscribed{reason=\"unsubscribed\"} 4\n"));
+        assert!(output.contains("newsman_send_duration_seconds_bucket{le=\"0.25\"} 1\n"));
+        assert!(output.contains("newsman_send_duration_seconds_bucket{le=\"5\"} 2\n"));
+        assert!(output.contains("newsman_send_duration_seconds_bucket{le=\"+Inf\"} 2\n"));
+        assert!(output.contains("newsman_http_requests_total{method=\"POST\",route=\"/api/add\",status=\"201\"} 2\n"));
+    }
+}
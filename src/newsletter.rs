@@ -0,0 +1,356 @@
+use std::collections::BTreeMap;
+use serde::Deserialize;
+use chrono::{DateTime, NaiveDate, NaiveDateTime};
+use log::debug;
+
+use crate::Config;
+
+/// Settings a newsletter can declare about itself at the top of its file, either as
+/// TOML between `+++` lines or YAML between `---` lines.
+#[derive(Deserialize, Default, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct FrontMatter {
+    pub subject: Option<String>,
+    pub preheader: Option<String>,
+    pub from_name: Option<String>,
+    pub from_address: Option<String>,
+    pub reply_to: Option<String>,
+    pub message_id_domain: Option<String>,
+    pub headers: BTreeMap<String, String>,
+    pub list: Option<String>,
+    pub segment: Option<String>,
+    pub send_at: Option<String>,
+    pub attachments: Vec<String>,
+    pub archive: Option<bool>,
+}
+
+/// A file sent along with a newsletter, inline parts are referenced from the body
+/// by their content id.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Attachment {
+    pub path: String,
+    pub content_id: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Newsletter {
+    pub front_matter: FrontMatter,
+    pub body: String,
+    pub attachments: Vec<Attachment>,
+}
+
+/// Parses a time given as RFC 3339, `YYYY-MM-DD HH:MM` or `YYYY-MM-DD` in UTC into a
+/// unix timestamp.
+pub fn parse_time(time: &str) -> Option<i64> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(time) {
+        return Some(time.timestamp());
+    }
+    if let Ok(time) = NaiveDateTime::parse_from_str(time, "%Y-%m-%d %H:%M") {
+        return Some(time.and_utc().timestamp());
+    }
+    NaiveDate::parse_from_str(time, "%Y-%m-%d")
+        .ok()
+        .and_then(|date| date.and_hms_opt(0, 0, 0))
+        .map(|time| time.and_utc().timestamp())
+}
+
+impl FrontMatter {
+    /// Parses `send_at` with [`parse_time`], returning a unix timestamp the same as the
+    /// jobs table stores.
+    pub fn send_time(&self) -> Result<Option<i64>, String> {
+        match self.send_at.as_deref() {
+            Some(send_at) => match parse_time(send_at) {
+                Some(time) => Ok(Some(time)),
+                None => Err(format!("Invalid send_at {:?} in front matter", send_at)),
+            },
+            None => Ok(None),
+        }
+    }
+}
+
+pub fn escape_html(value: &str) -> String {
+    value.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+/// Replaces `{{ name }}` placeholders with the subscriber's values, `{{ name | default }}`
+/// falls back to the default when the value is missing or empty. Values are escaped
+/// when filling in html.
+pub fn fill(template: &str, values: &BTreeMap<String, String>, html: bool) -> String {
+    let mut output: String = String::new();
+    let mut rest: &str = template;
+
+    while let Some(start) = rest.find("{{") {
+        let end = match rest[start..].find("}}") {
+            Some(end) => start + end,
+            None => break,
+        };
+        output.push_str(&rest[..start]);
+        let placeholder: &str = &rest[start + 2..end];
+        let (name, default) = match placeholder.split_once('|') {
+            Some((name, default)) => (name.trim(), default.trim()),
+            None => (placeholder.trim(), ""),
+        };
+        let value: &str = match values.get(name) {
+            Some(value) if !value.is_empty() => value,
+            _ => default,
+        };
+        if html {
+            output.push_str(&escape_html(value));
+        } else {
+            output.push_str(value);
+        }
+        rest = &rest[end + 2..];
+    }
+    output.push_str(rest);
+
+    output
+}
+
+/// Whether a source is a file next to the newsletter rather than a url, which is
+/// anything with a scheme such as `https:`, `data:` or `cid:`, or protocol-relative.
+fn is_local(src: &str) -> bool {
+    let scheme: Option<&str> = src.split_once(':').map(|(scheme, _)| scheme);
+    let has_scheme: bool = scheme.is_some_and(|scheme| {
+        scheme.starts_with(|c: char| c.is_ascii_alphabetic())
+            && scheme.chars().all(|c| c.is_ascii_alphanumeric() || c == '+' || c == '-' || c == '.')
+    });
+    !(src.is_empty() || src.starts_with("//") || has_scheme)
+}
+
+/// Finds the value of a tag's `src` attribute, quoted either way, returning where it
+/// starts and ends inside the tag.
+fn find_src(tag: &str) -> Option<(usize, usize)> {
+    let lower: String = tag.to_ascii_lowercase();
+    let mut from: usize = 0;
+    while let Some(found) = lower[from..].find("src=") {
+        let start = from + found;
+        from = start + 4;
+        // skip data-src= and the like
+        if !lower[..start].ends_with(|c: char| c.is_ascii_whitespace()) {
+            continue;
+        }
+        let quote = match tag[from..].chars().next() {
+            Some(quote) if quote == '"' || quote == '\'' => quote,
+            _ => continue,
+        };
+        let end = tag[from + 1..].find(quote)?;
+        return Some((from + 1, from + 1 + end));
+    }
+    None
+}
+
+/// Replaces the `src` of every locally referenced `<img>` with a `cid:` reference and
+/// returns the images that need embedding.
+fn inline_images(body: &str) -> (String, Vec<Attachment>) {
+    let mut output: String = String::new();
+    let mut images: Vec<Attachment> = Vec::new();
+    let lower: String = body.to_ascii_lowercase();
+    let mut position: usize = 0;
+
+    while let Some(found) = lower[position..].find("<img") {
+        let tag_start = position + found;
+        let tag_end = match body[tag_start..].find('>') {
+            Some(end) => tag_start + end,
+            None => break,
+        };
+        let tag = &body[tag_start..tag_end];
+        if let Some((start, end)) = find_src(tag) {
+            let src = &tag[start..end];
+            if is_local(src) {
+                let content_id = match images.iter().find(|image| image.path == src) {
+                    Some(image) => image.content_id.clone().unwrap(),
+                    None => {
+                        let content_id = format!("image{}@newsman", images.len());
+                        images.push(Attachment { path: src.to_string(), content_id: Some(content_id.clone()) });
+                        content_id
+                    }
+                };
+                output.push_str(&body[position..tag_start + start]);
+                output.push_str(&format!("cid:{}", content_id));
+                position = tag_start + end;
+            }
+        }
+        output.push_str(&body[position..tag_end]);
+        position = tag_end;
+    }
+    output.push_str(&body[position..]);
+
+    (output, images)
+}
+
+fn build(front_matter: FrontMatter, body: &str) -> Newsletter {
+    let (body, mut attachments) = inline_images(body);
+    for path in &front_matter.attachments {
+        attachments.push(Attachment { path: path.clone(), content_id: None });
+    }
+
+    Newsletter { front_matter, body, attachments }
+}
+
+fn split_front_matter<'a>(contents: &'a str, fence: &str) -> Option<(&'a str, &'a str)> {
+    let rest = contents.strip_prefix(fence)?;
+    let rest = rest.strip_prefix("\r\n").or_else(|| rest.strip_prefix('\n'))?;
+    let mut offset = 0;
+    for line in rest.split_inclusive('\n') {
+        if line.trim_end() == fence {
+            return Some((&rest[..offset], &rest[offset + line.len()..]));
+        }
+        offset += line.len();
+    }
+    None
+}
+
+pub fn parse(contents: &str) -> Result<Newsletter, String> {
+    if let Some((header, body)) = split_front_matter(contents, "+++") {
+        debug!("found toml front matter");
+        return match toml::from_str::<FrontMatter>(header) {
+            Ok(front_matter) => Ok(build(front_matter, body)),
+            Err(err) => Err(format!("Error reading front matter: {}", err)),
+        };
+    }
+
+    if let Some((header, body)) = split_front_matter(contents, "---") {
+        debug!("found yaml front matter");
+        return match serde_yaml::from_str::<Option<FrontMatter>>(header) {
+            Ok(front_matter) => Ok(build(front_matter.unwrap_or_default(), body)),
+            Err(err) => Err(format!("Error reading front matter: {}", err)),
+        };
+    }
+
+    Ok(build(FrontMatter::default(), contents))
+}
+
+pub fn load(newsletter: &str) -> Result<Newsletter, String> {
+    let config: Config = Config::load_config()?;
+
+    let mut newsletter: Newsletter = match std::fs::read_to_string(format!("{}{}", config.dir, newsletter)) {
+        Ok(contents) => parse(&contents)?,
+        Err(err) => return Err(format!("Cannot open newsletter {}: {}", newsletter, err)),
+    };
+    for attachment in newsletter.attachments.iter_mut() {
+        if !attachment.path.starts_with('/') {
+            attachment.path = format!("{}{}", config.dir, attachment.path);
+        }
+    }
+
+    Ok(newsletter)
+}
+
+/// How big `size` bytes get once base64 encoded into 76 character lines.
+fn encoded_size(size: u64) -> u64 {
+    let encoded: u64 = size.div_ceil(3) * 4;
+    encoded + encoded.div_ceil(76) * 2
+}
+
+/// Makes sure every attachment exists and fits inside the configured size limits,
+/// returning the combined size in bytes. Attachments count at their base64 encoded
+/// size towards the message limit, as that's how they're sent.
+pub fn check_attachments(newsletter: &Newsletter, config: &Config) -> Result<u64, String> {
+    let mut total: u64 = newsletter.body.len() as u64;
+
+    for attachment in &newsletter.attachments {
+        let size: u64 = match std::fs::metadata(&attachment.path) {
+            Ok(metadata) => metadata.len(),
+            Err(err) => return Err(format!("Cannot open attachment {}: {}", attachment.path, err)),
+        };
+        if size > config.max_attachment_size {
+            return Err(format!("Attachment {} is {} bytes, the limit is {}",
+                               attachment.path, size, config.max_attachment_size));
+        }
+        total += encoded_size(size);
+    }
+
+    if total > config.max_message_size {
+        return Err(format!("Newsletter is {} bytes with attachments, the limit is {}",
+                           total, config.max_message_size));
+    }
+
+    Ok(total)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_toml_front_matter() {
+        let newsletter = parse("+++\nsubject = \"Hello\"\nlist = \"weekly\"\n+++\n<p>body</p>\n").unwrap();
+
+        assert_eq!(Some(String::from("Hello")), newsletter.front_matter.subject);
+        assert_eq!(Some(String::from("weekly")), newsletter.front_matter.list);
+        assert_eq!("<p>body</p>\n", newsletter.body);
+    }
+
+    #[test]
+    fn parse_yaml_front_matter() {
+        let newsletter = parse("---\nsubject: Hello\nreply_to: editor@example.com\nsend_at: 2026-01-01 09:30\n---\n<p>body</p>").unwrap();
+
+        assert_eq!(Some(String::from("editor@example.com")), newsletter.front_matter.reply_to);
+        assert_eq!(Some(1767259800), newsletter.front_matter.send_time().unwrap());
+        assert_eq!("<p>body</p>", newsletter.body);
+    }
+
+    #[test]
+    fn parse_attachments_and_inline_images() {
+        let newsletter = parse("+++\nattachments = [\"report.pdf\"]\n+++\n<img src=\"logo.png\"><img src=\"https://example.com/a.png\"><img src=\"logo.png\">").unwrap();
+
+        assert_eq!("<img src=\"cid:image0@newsman\"><img src=\"https://example.com/a.png\"><img src=\"cid:image0@newsman\">", newsletter.body);
+        assert_eq!(vec![
+                   Attachment { path: String::from("logo.png"), content_id: Some(String::from("image0@newsman")) },
+                   Attachment { path: String::from("report.pdf"), content_id: None },
+        ], newsletter.attachments);
+    }
+
+    #[test]
+    fn inline_only_img_sources() {
+        let newsletter = parse("<IMG alt=\"x\" src='logo.png'><img data-src=\"lazy.png\" src=\"a.png\">\
+                                <script src=\"app.js\"></script><iframe src=\"frame.html\"></iframe>").unwrap();
+
+        assert_eq!("<IMG alt=\"x\" src='cid:image0@newsman'><img data-src=\"lazy.png\" src=\"cid:image1@newsman\">\
+                    <script src=\"app.js\"></script><iframe src=\"frame.html\"></iframe>", newsletter.body);
+        assert_eq!(2, newsletter.attachments.len());
+    }
+
+    #[test]
+    fn local_sources() {
+        assert!(is_local("logo.png"));
+        assert!(is_local("images/logo.png"));
+        assert!(!is_local("https://cdn.example.com/logo.png"));
+        assert!(!is_local("//cdn.example.com/logo.png"));
+        assert!(!is_local("data:image/png;base64,AAAA"));
+        assert!(!is_local("cid:image0@newsman"));
+        assert!(!is_local(""));
+    }
+
+    #[test]
+    fn base64_encoded_size() {
+        assert_eq!(0, encoded_size(0));
+        assert_eq!(6, encoded_size(3));
+        assert_eq!(78, encoded_size(57));
+        assert_eq!(84, encoded_size(58));
+    }
+
+    #[test]
+    fn fill_placeholders() {
+        let values = BTreeMap::from([
+            (String::from("name"), String::from("Ann <Admin>")),
+            (String::from("country"), String::new()),
+        ]);
+
+        assert_eq!("<p>Hi Ann &lt;Admin&gt;, from there</p>", fill("<p>Hi {{ name }}, from {{country|there}}</p>", &values, true));
+        assert_eq!("Hi Ann <Admin>{{ name", fill("Hi {{name}}{{ name", &values, false));
+        assert_eq!("Hi ", fill("Hi {{ plan }}", &values, false));
+    }
+
+    #[test]
+    fn parse_without_front_matter() {
+        let newsletter = parse("<p>---</p>").unwrap();
+
+        assert_eq!(FrontMatter::default(), newsletter.front_matter);
+        assert_eq!("<p>---</p>", newsletter.body);
+    }
+}
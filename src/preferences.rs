@@ -0,0 +1,361 @@
+use std::collections::{BTreeMap, BTreeSet};
+use sqlx::mysql::{MySqlPool, MySqlPoolOptions};
+use chrono::Utc;
+use log::debug;
+
+use crate::Config;
+use crate::emails::{remove_email_with_token, update_subscriber, SubscriberChanges};
+use crate::newsletter::{escape_html, fill};
+use crate::subscribers::{load_subscriber, Subscriber};
+
+pub const ALL: &str = "all";
+pub const WEEKLY: &str = "weekly";
+pub const MONTHLY: &str = "monthly";
+pub const FREQUENCIES: [&str; 3] = [ALL, WEEKLY, MONTHLY];
+
+const DAY: i64 = 24 * 60 * 60;
+
+/// Whether a subscriber with this frequency can be sent another newsletter, weekly and
+/// monthly subscribers are skipped until a week or 30 days after the last one.
+pub fn allows(frequency: &str, last_sent: Option<i64>, now: i64) -> bool {
+    let gap: i64 = match frequency {
+        WEEKLY => 7 * DAY,
+        MONTHLY => 30 * DAY,
+        _ => 0,
+    };
+    last_sent.is_none_or(|sent| sent <= now - gap)
+}
+
+/// What a subscriber chose on their preference page.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Preferences {
+    pub name: Option<String>,
+    pub locale: Option<String>,
+    pub frequency: String,
+    pub lists: Vec<String>,
+    pub unsubscribe: bool,
+    pub reason: Option<String>,
+}
+
+/// The lists shown on the preference page, the ones in the config along with any
+/// other list the subscriber is already on so they can leave it.
+pub fn available_lists(subscriber: &Subscriber, config: &Config) -> BTreeSet<String> {
+    config.lists.iter()
+        .chain(subscriber.lists.iter())
+        .cloned()
+        .collect()
+}
+
+pub fn is_valid_locale(locale: &str) -> bool {
+    !locale.is_empty() && locale.len() <= 35
+        && locale.split(['-', '_']).all(|part| !part.is_empty() && part.chars().all(|c| c.is_ascii_alphanumeric()))
+}
+
+impl Preferences {
+    /// Checks the choices against what the preference page offers this subscriber.
+    pub fn check(&self, subscriber: &Subscriber, config: &Config) -> Result<(), String> {
+        if !FREQUENCIES.contains(&self.frequency.as_str()) {
+            return Err(format!("Unknown frequency {:?}, choose {}", self.frequency, FREQUENCIES.join(", ")));
+        }
+        if self.name.as_ref().is_some_and(|name| name.len() > 255) {
+            return Err(String::from("Name must be 255 characters or less"));
+        }
+        if let Some(locale) = &self.locale {
+            if !is_valid_locale(locale) || (!config.locales.is_empty() && !config.locales.contains(locale)) {
+                return Err(format!("Unknown language {:?}", locale));
+            }
+        }
+        if self.reason.as_ref().is_some_and(|reason| reason.len() > 1000) {
+            return Err(String::from("Reason must be 1000 characters or less"));
+        }
+        let lists: BTreeSet<String> = available_lists(subscriber, config);
+        match self.lists.iter().find(|list| !lists.contains(*list)) {
+            Some(list) => Err(format!("Unknown list {}", list)),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Loads the subscriber a preference link was sent to.
+pub async fn load_with_token(pool: &MySqlPool, token: &str) -> Result<Option<Subscriber>, String> {
+    match sqlx::query!(r#"
+                       SELECT email FROM mailing_list WHERE token = (?)"#,
+                       token)
+        .fetch_optional(pool)
+        .await {
+            Ok(Some(record)) => load_subscriber(pool, &record.email).await,
+            Ok(None) => Ok(None),
+            Err(err) => Err(format!("Error reading subscriber: {}", err)),
+        }
+}
+
+async fn save(pool: &MySqlPool, subscriber: &Subscriber, preferences: &Preferences, config: &Config) -> Result<(), String> {
+    debug!("saving preferences for {}", subscriber.token);
+    // every list the subscriber is on is shown, so the ticked ones are all they keep
+    let changes = SubscriberChanges {
+        name: Some(preferences.name.clone().unwrap_or_default()),
+        locale: Some(preferences.locale.clone().unwrap_or_default()),
+        frequency: Some(preferences.frequency.clone()),
+        lists: Some(preferences.lists.clone()),
+        ..Default::default()
+    };
+    update_subscriber(pool, config, subscriber, &changes).await.map_err(|err| err.to_string())
+}
+
+/// Reasons are kept apart from the email so they can be read without knowing who
+/// gave them.
+async fn record_reason(pool: &MySqlPool, reason: &str) -> Result<(), String> {
+    match sqlx::query!(r#"
+                       INSERT INTO unsubscribe_feedback (reason, time) VALUES (?, ?)"#,
+                       reason,
+                       Utc::now().timestamp())
+        .execute(pool)
+        .await {
+            Ok(_) => Ok(()),
+            Err(err) => Err(format!("Error recording unsubscribe reason: {}", err)),
+        }
+}
+
+/// What happened when a subscriber submitted their preference page.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Outcome {
+    Saved(Subscriber),
+    Unsubscribed(String),
+    Invalid(Subscriber, String),
+    NotFound,
+}
+
+/// Saves a subscriber's preferences, or unsubscribes them entirely.
+pub async fn update(token: String, preferences: Preferences) -> Result<Outcome, String> {
+    let config: Config = Config::load_config().unwrap();
+
+    let pool = MySqlPoolOptions::new()
+        .max_connections(5)
+        .connect(&config.url)
+        .await
+        .expect("Cannot connect to database!");
+
+    let subscriber: Subscriber = match load_with_token(&pool, &token).await? {
+        Some(subscriber) => subscriber,
+        None => return Ok(Outcome::NotFound),
+    };
+    if let Err(err) = preferences.check(&subscriber, &config) {
+        return Ok(Outcome::Invalid(subscriber, err));
+    }
+
+    if preferences.unsubscribe {
+        if let Some(reason) = &preferences.reason {
+            record_reason(&pool, reason).await?;
+        }
+        remove_email_with_token(token).await?;
+        return Ok(Outcome::Unsubscribed(subscriber.email));
+    }
+
+    save(&pool, &subscriber, &preferences, &config).await?;
+    match load_with_token(&pool, &token).await? {
+        Some(subscriber) => Ok(Outcome::Saved(subscriber)),
+        None => Ok(Outcome::NotFound),
+    }
+}
+
+/// Looks up the subscriber for the preference page.
+pub async fn find(token: String) -> Result<Option<Subscriber>, String> {
+    let config: Config = Config::load_config().unwrap();
+
+    let pool = MySqlPoolOptions::new()
+        .max_connections(5)
+        .connect(&config.url)
+        .await
+        .expect("Cannot connect to database!");
+
+    load_with_token(&pool, &token).await
+}
+
+fn option(value: &str, label: &str, selected: bool) -> String {
+    format!("<option value=\"{}\"{}>{}</option>",
+            escape_html(value),
+            if selected { " selected" } else { "" },
+            escape_html(label))
+}
+
+/// Wraps a fragment of html in the page shared by every preference response.
+pub fn page(title: &str, content: &str) -> String {
+    format!("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n\
+             <meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">\n\
+             <title>{}</title>\n</head>\n<body>\n<h1>{}</h1>\n{}\n</body>\n</html>\n",
+            escape_html(title),
+            escape_html(title),
+            content)
+}
+
+/// Renders the preference form for a subscriber, with an optional notice above it.
+pub fn render(subscriber: &Subscriber, config: &Config, notice: Option<&str>) -> String {
+    let mut content: String = String::new();
+    if let Some(notice) = notice {
+        content.push_str(&format!("<p><strong>{}</strong></p>\n", escape_html(notice)));
+    }
+    content.push_str(&format!("<p>Preferences for {}</p>\n<form method=\"post\">\n", escape_html(&subscriber.email)));
+    content.push_str(&format!("<p><label>Name <input type=\"text\" name=\"name\" value=\"{}\"></label></p>\n",
+                              escape_html(subscriber.name.as_deref().unwrap_or_default())));
+
+    let locale: &str = subscriber.locale.as_deref().unwrap_or_default();
+    if config.locales.is_empty() {
+        content.push_str(&format!("<p><label>Language <input type=\"text\" name=\"locale\" value=\"{}\"></label></p>\n",
+                                  escape_html(locale)));
+    } else {
+        let mut options: String = option("", "Default", locale.is_empty());
+        for available in &config.locales {
+            options.push_str(&option(available, available, available == locale));
+        }
+        content.push_str(&format!("<p><label>Language <select name=\"locale\">{}</select></label></p>\n", options));
+    }
+
+    let frequency: &str = if subscriber.frequency.is_empty() { ALL } else { &subscriber.frequency };
+    let options: String = [(ALL, "Every newsletter"), (WEEKLY, "At most once a week"), (MONTHLY, "At most once a month")]
+        .iter()
+        .map(|(value, label)| option(value, label, *value == frequency))
+        .collect();
+    content.push_str(&format!("<p><label>How often <select name=\"frequency\">{}</select></label></p>\n", options));
+
+    let lists: BTreeSet<String> = available_lists(subscriber, config);
+    if !lists.is_empty() {
+        content.push_str("<fieldset>\n<legend>Lists</legend>\n");
+        for list in &lists {
+            content.push_str(&format!("<label><input type=\"checkbox\" name=\"list\" value=\"{}\"{}> {}</label><br>\n",
+                                      escape_html(list),
+                                      if subscriber.lists.contains(list) { " checked" } else { "" },
+                                      escape_html(list)));
+        }
+        content.push_str("</fieldset>\n");
+    }
+
+    content.push_str("<p><button type=\"submit\" name=\"action\" value=\"save\">Save preferences</button></p>\n");
+    content.push_str("<h2>Unsubscribe</h2>\n\
+                      <p><label>Why are you leaving? (optional)<br><textarea name=\"reason\" rows=\"3\" cols=\"40\"></textarea></label></p>\n\
+                      <p><button type=\"submit\" name=\"action\" value=\"unsubscribe\">Unsubscribe from everything</button></p>\n\
+                      </form>");
+
+    page("Email preferences", &content)
+}
+
+/// Asks the subscriber to confirm unsubscribing, the link in a newsletter only shows this
+/// page so mail scanners following it don't unsubscribe anyone.
+pub fn render_unsubscribe(subscriber: &Subscriber, config: &Config) -> String {
+    let content: String = format!("<p>Stop sending newsletters to {}?</p>\n\
+                                   <form method=\"post\">\n\
+                                   <p><button type=\"submit\">Unsubscribe</button></p>\n\
+                                   </form>\n\
+                                   <p>Or <a href=\"{}preferences/{}\">choose which emails you get</a> instead.</p>",
+                                  escape_html(&subscriber.email),
+                                  escape_html(&config.api_endpoint),
+                                  escape_html(&subscriber.token));
+    page("Unsubscribe", &content)
+}
+
+/// Asks someone who followed a resubscribe link to confirm it.
+pub fn render_resubscribe(email: &str, token: &str) -> String {
+    let content: String = format!("<p>Start sending newsletters to {} again?</p>\n\
+                                   <form method=\"post\">\n\
+                                   <input type=\"hidden\" name=\"token\" value=\"{}\">\n\
+                                   <p><button type=\"submit\">Subscribe</button></p>\n\
+                                   </form>",
+                                  escape_html(email),
+                                  escape_html(token));
+    page("Subscribe again", &content)
+}
+
+/// The page shown once someone has unsubscribed, filled in from the config's
+/// `unsubscribe_template` when one is set. Templates can use `{{ email }}` and
+/// `{{ website }}`.
+pub fn render_unsubscribed(email: &str, config: &Config) -> Result<String, String> {
+    let values: BTreeMap<String, String> = BTreeMap::from([
+        (String::from("email"), email.to_string()),
+        (String::from("website"), config.api_redirect_unsubscribe.clone()),
+    ]);
+    match config.unsubscribe_template.as_deref() {
+        Some(path) => match std::fs::read_to_string(path) {
+            Ok(template) => Ok(fill(&template, &values, true)),
+            Err(err) => Err(format!("Cannot open unsubscribe template {}: {}", path, err)),
+        },
+        None => Ok(page("Unsubscribed", &fill("<p>{{ email }} won't receive any more newsletters.</p>\n\
+                                                <p><a href=\"{{ website }}\">Return to the website</a></p>",
+                                               &values,
+                                               true))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn subscriber() -> Subscriber {
+        Subscriber {
+            token: String::from("token"),
+            email: String::from("example@test.com"),
+            name: Some(String::from("<Example>")),
+            frequency: String::from(WEEKLY),
+            lists: vec![String::from("weekly")],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn frequency_limits_sends() {
+        let now = 100 * DAY;
+
+        assert!(allows(ALL, Some(now), now));
+        assert!(allows(WEEKLY, None, now));
+        assert!(!allows(WEEKLY, Some(now - 6 * DAY), now));
+        assert!(allows(WEEKLY, Some(now - 7 * DAY), now));
+        assert!(!allows(MONTHLY, Some(now - 29 * DAY), now));
+    }
+
+    #[test]
+    fn check_preferences() {
+        let mut config = Config {
+            lists: vec![String::from("monthly")],
+            locales: vec![String::from("en"), String::from("de-AT")],
+            ..Default::default()
+        };
+        let preferences = Preferences {
+            locale: Some(String::from("de-AT")),
+            frequency: String::from(MONTHLY),
+            lists: vec![String::from("monthly"), String::from("weekly")],
+            ..Default::default()
+        };
+
+        assert_eq!(Ok(()), preferences.check(&subscriber(), &config));
+        assert!(Preferences { frequency: String::from("daily"), ..preferences.clone() }.check(&subscriber(), &config).is_err());
+        assert!(Preferences { locale: Some(String::from("fr")), ..preferences.clone() }.check(&subscriber(), &config).is_err());
+        assert!(Preferences { lists: vec![String::from("secret")], ..preferences.clone() }.check(&subscriber(), &config).is_err());
+        config.locales.clear();
+        assert!(Preferences { locale: Some(String::from("fr\"><script>")), ..preferences }.check(&subscriber(), &config).is_err());
+    }
+
+    #[test]
+    fn render_preference_page() {
+        let config = Config { lists: vec![String::from("monthly")], ..Default::default() };
+        let page = render(&subscriber(), &config, Some("Your preferences have been saved"));
+
+        assert!(page.contains("value=\"&lt;Example&gt;\""));
+        assert!(page.contains("<option value=\"weekly\" selected>"));
+        assert!(page.contains("value=\"monthly\"> monthly"));
+        assert!(page.contains("value=\"weekly\" checked> weekly"));
+        assert!(page.contains("Your preferences have been saved"));
+    }
+
+    #[test]
+    fn render_unsubscribe_pages() {
+        let mut config = Config::default();
+        let confirm = render_unsubscribe(&subscriber(), &config);
+        assert!(confirm.contains("<form method=\"post\">"));
+        assert!(confirm.contains("preferences/token"));
+
+        let unsubscribed = render_unsubscribed("example@test.com", &config).unwrap();
+        assert!(unsubscribed.contains("example@test.com won't receive"));
+        assert!(unsubscribed.contains("href=\"http://your.website.here/\""));
+
+        config.unsubscribe_template = Some(String::from("/nonexistent/unsubscribed.html"));
+        assert!(render_unsubscribed("example@test.com", &config).is_err());
+    }
+}
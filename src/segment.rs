@@ -0,0 +1,405 @@
+use std::collections::HashMap;
+use sqlx::mysql::{MySqlPool, MySqlPoolOptions};
+use chrono::Utc;
+use log::debug;
+
+use crate::Config;
+use crate::subscribers::{load_subscribers, Subscriber};
+use crate::suppression::SUBSCRIBED;
+
+/// When a subscriber was last sent, opened or bounced a newsletter.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Activity {
+    pub sent: Option<i64>,
+    pub opened: Option<i64>,
+    pub bounced: Option<i64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Event {
+    Sent,
+    Opened,
+    Bounced,
+    SignedUp,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Op {
+    Equal,
+    NotEqual,
+    Greater,
+    GreaterEqual,
+    Less,
+    LessEqual,
+}
+
+/// A filter expression over subscribers, such as
+/// `tag:beta AND country=DE AND NOT opened_last:90d`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Segment {
+    And(Box<Segment>, Box<Segment>),
+    Or(Box<Segment>, Box<Segment>),
+    Not(Box<Segment>),
+    Tag(String),
+    List(String),
+    Status(String),
+    Within(Event, i64),
+    Compare(String, Op, String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Open,
+    Close,
+    Word(String),
+}
+
+fn tokenise(expression: &str) -> Result<Vec<Token>, String> {
+    let mut tokens: Vec<Token> = Vec::new();
+    let mut word: String = String::new();
+    let mut chars = expression.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '(' | ')' | ' ' | '\t' | '\n' => {
+                if !word.is_empty() {
+                    tokens.push(Token::Word(std::mem::take(&mut word)));
+                }
+                match c {
+                    '(' => tokens.push(Token::Open),
+                    ')' => tokens.push(Token::Close),
+                    _ => (),
+                }
+            },
+            '"' => {
+                // quoted values can hold spaces and brackets
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some(c) => word.push(c),
+                        None => return Err(String::from("Unclosed quote in segment")),
+                    }
+                }
+            },
+            c => word.push(c),
+        }
+    }
+    if !word.is_empty() {
+        tokens.push(Token::Word(word));
+    }
+
+    Ok(tokens)
+}
+
+/// Parses a duration such as `90d`, `12h` or `2w` into seconds.
+fn parse_duration(duration: &str) -> Result<i64, String> {
+    let units: [(char, i64); 3] = [('h', 60 * 60), ('d', 24 * 60 * 60), ('w', 7 * 24 * 60 * 60)];
+    let (number, seconds): (&str, i64) = match units.iter().find_map(|(unit, seconds)| Some((duration.strip_suffix(*unit)?, *seconds))) {
+        Some(found) => found,
+        None => return Err(format!("Invalid duration {:?}, use a number of h, d or w", duration)),
+    };
+    match number.parse::<i64>().ok().filter(|number| *number >= 0) {
+        Some(number) => number.checked_mul(seconds).ok_or(format!("Duration {:?} is too long", duration)),
+        None => Err(format!("Invalid duration {:?}, use a number of h, d or w", duration)),
+    }
+}
+
+fn parse_term(term: &str) -> Result<Segment, String> {
+    for (symbol, op) in [(">=", Op::GreaterEqual), ("<=", Op::LessEqual), ("!=", Op::NotEqual),
+                         ("=", Op::Equal), (">", Op::Greater), ("<", Op::Less)] {
+        if let Some((name, value)) = term.split_once(symbol) {
+            if name.is_empty() {
+                return Err(format!("Missing field name in {:?}", term));
+            }
+            return Ok(Segment::Compare(name.to_string(), op, value.to_string()));
+        }
+    }
+
+    match term.split_once(':') {
+        Some(("tag", tag)) if !tag.is_empty() => Ok(Segment::Tag(tag.to_string())),
+        Some(("list", list)) if !list.is_empty() => Ok(Segment::List(list.to_string())),
+        Some(("status", status)) if !status.is_empty() => Ok(Segment::Status(status.to_string())),
+        Some(("sent_last", duration)) => Ok(Segment::Within(Event::Sent, parse_duration(duration)?)),
+        Some(("opened_last", duration)) => Ok(Segment::Within(Event::Opened, parse_duration(duration)?)),
+        Some(("bounced_last", duration)) => Ok(Segment::Within(Event::Bounced, parse_duration(duration)?)),
+        Some(("signed_up_last", duration)) => Ok(Segment::Within(Event::SignedUp, parse_duration(duration)?)),
+        _ => Err(format!("Unknown condition {:?}", term)),
+    }
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+}
+
+impl Parser {
+    fn peek_keyword(&self, keyword: &str) -> bool {
+        matches!(self.tokens.get(self.position), Some(Token::Word(word)) if word.eq_ignore_ascii_case(keyword))
+    }
+
+    fn or(&mut self) -> Result<Segment, String> {
+        let mut segment: Segment = self.and()?;
+        while self.peek_keyword("OR") {
+            self.position += 1;
+            segment = Segment::Or(Box::new(segment), Box::new(self.and()?));
+        }
+        Ok(segment)
+    }
+
+    fn and(&mut self) -> Result<Segment, String> {
+        let mut segment: Segment = self.not()?;
+        while self.peek_keyword("AND") {
+            self.position += 1;
+            segment = Segment::And(Box::new(segment), Box::new(self.not()?));
+        }
+        Ok(segment)
+    }
+
+    fn not(&mut self) -> Result<Segment, String> {
+        if self.peek_keyword("NOT") {
+            self.position += 1;
+            return Ok(Segment::Not(Box::new(self.not()?)));
+        }
+        self.term()
+    }
+
+    fn term(&mut self) -> Result<Segment, String> {
+        let token: Option<Token> = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        match token {
+            Some(Token::Open) => {
+                let segment: Segment = self.or()?;
+                match self.tokens.get(self.position) {
+                    Some(Token::Close) => {
+                        self.position += 1;
+                        Ok(segment)
+                    },
+                    _ => Err(String::from("Missing ) in segment")),
+                }
+            },
+            Some(Token::Word(word)) if ["AND", "OR", "NOT"].iter().any(|keyword| word.eq_ignore_ascii_case(keyword)) => {
+                Err(format!("Expected a condition before {}", word))
+            },
+            Some(Token::Word(word)) => parse_term(&word),
+            Some(Token::Close) => Err(String::from("Unexpected ) in segment")),
+            None => Err(String::from("Segment ends early, expected a condition")),
+        }
+    }
+}
+
+/// Compares numbers as numbers and anything else, including YYYY-MM-DD dates, as text.
+fn compare(value: &str, op: Op, expected: &str) -> bool {
+    let ordering = match (value.parse::<f64>(), expected.parse::<f64>()) {
+        (Ok(value), Ok(expected)) => value.partial_cmp(&expected),
+        _ => Some(value.cmp(expected)),
+    };
+    match ordering {
+        Some(ordering) => match op {
+            Op::Equal => ordering.is_eq(),
+            Op::NotEqual => ordering.is_ne(),
+            Op::Greater => ordering.is_gt(),
+            Op::GreaterEqual => ordering.is_ge(),
+            Op::Less => ordering.is_lt(),
+            Op::LessEqual => ordering.is_le(),
+        },
+        None => false,
+    }
+}
+
+impl Segment {
+    pub fn parse(expression: &str) -> Result<Segment, String> {
+        let mut parser = Parser { tokens: tokenise(expression)?, position: 0 };
+        let segment: Segment = parser.or()?;
+        match parser.tokens.get(parser.position) {
+            None => Ok(segment),
+            Some(Token::Word(word)) => Err(format!("Expected AND or OR before {:?}", word)),
+            Some(_) => Err(String::from("Unexpected ) in segment")),
+        }
+    }
+
+    /// Whether the subscriber is in the segment at the given time.
+    pub fn matches(&self, subscriber: &Subscriber, activity: &Activity, now: i64) -> bool {
+        match self {
+            Segment::And(left, right) => left.matches(subscriber, activity, now) && right.matches(subscriber, activity, now),
+            Segment::Or(left, right) => left.matches(subscriber, activity, now) || right.matches(subscriber, activity, now),
+            Segment::Not(segment) => !segment.matches(subscriber, activity, now),
+            Segment::Tag(tag) => subscriber.tags.contains(tag),
+            Segment::List(list) => subscriber.lists.contains(list),
+            Segment::Status(status) => &subscriber.status == status,
+            Segment::Within(event, duration) => {
+                let time: Option<i64> = match event {
+                    Event::Sent => activity.sent,
+                    Event::Opened => activity.opened,
+                    Event::Bounced => activity.bounced,
+                    Event::SignedUp => Some(subscriber.created).filter(|created| *created > 0),
+                };
+                time.is_some_and(|time| time >= now.saturating_sub(*duration))
+            },
+            Segment::Compare(name, op, expected) => match subscriber.template_values().get(name) {
+                Some(value) => compare(value, *op, expected),
+                None => *op == Op::NotEqual,
+            },
+        }
+    }
+}
+
+/// Loads the latest sent, opened and bounced times for every subscriber.
+pub async fn load_activity(pool: &MySqlPool) -> Result<HashMap<String, Activity>, String> {
+    let mut activity: HashMap<String, Activity> = HashMap::new();
+
+    match sqlx::query!(r#"
+                       SELECT token, MAX(time) AS last FROM deliveries WHERE status = 'sent' GROUP BY token"#)
+        .fetch_all(pool)
+        .await {
+            Ok(records) => for record in records {
+                activity.entry(record.token).or_default().sent = record.last;
+            },
+            Err(err) => return Err(format!("Error reading deliveries: {}", err)),
+        }
+
+    match sqlx::query!(r#"
+                       SELECT token, MAX(time) AS last FROM opens GROUP BY token"#)
+        .fetch_all(pool)
+        .await {
+            Ok(records) => for record in records {
+                activity.entry(record.token).or_default().opened = record.last;
+            },
+            Err(err) => return Err(format!("Error reading opens: {}", err)),
+        }
+
+    match sqlx::query!(r#"
+                       SELECT mailing_list.token, MAX(bounces.time) AS last FROM bounces
+                       JOIN mailing_list ON mailing_list.email = bounces.email
+                       GROUP BY mailing_list.token"#)
+        .fetch_all(pool)
+        .await {
+            Ok(records) => for record in records {
+                activity.entry(record.token).or_default().bounced = record.last;
+            },
+            Err(err) => return Err(format!("Error reading bounces: {}", err)),
+        }
+
+    Ok(activity)
+}
+
+/// Records a subscriber opening a newsletter, from the tracking image.
+pub async fn record_open(token: String, job: i64) -> Result<(), String> {
+    let config: Config = Config::load_config().unwrap();
+
+    let pool = MySqlPoolOptions::new()
+        .max_connections(5)
+        .connect(&config.url)
+        .await
+        .expect("Cannot connect to database!");
+
+    match sqlx::query!(r#"
+                       INSERT INTO opens (token, job, time)
+                       SELECT token, ?, ? FROM mailing_list WHERE token = (?)"#,
+                       job,
+                       Utc::now().timestamp(),
+                       token)
+        .execute(&pool)
+        .await {
+            Ok(_) => Ok(()),
+            Err(err) => Err(format!("Error recording open: {}", err)),
+        }
+}
+
+/// Narrows down recipients to the ones in the segment.
+pub async fn select(pool: &MySqlPool, segment: &Segment, subscribers: Vec<Subscriber>) -> Result<Vec<Subscriber>, String> {
+    let activity: HashMap<String, Activity> = load_activity(pool).await?;
+    let now: i64 = Utc::now().timestamp();
+    let none: Activity = Activity::default();
+
+    Ok(subscribers.into_iter()
+       .filter(|subscriber| segment.matches(subscriber, activity.get(&subscriber.token).unwrap_or(&none), now))
+       .collect())
+}
+
+/// Shows how many subscribed emails are in a segment along with a sample of them.
+pub async fn preview_segment(expression: String) -> Result<String, String> {
+    let config: Config = Config::load_config().unwrap();
+    let segment: Segment = Segment::parse(&expression)?;
+    debug!("previewing segment {:?}", segment);
+
+    let pool = MySqlPoolOptions::new()
+        .max_connections(5)
+        .connect(&config.url)
+        .await
+        .expect("Cannot connect to database!");
+
+    let mut subscribers: Vec<Subscriber> = load_subscribers(&pool).await?;
+    subscribers.retain(|subscriber| subscriber.status == SUBSCRIBED);
+    let matching: Vec<Subscriber> = select(&pool, &segment, subscribers).await?;
+
+    let mut output: String = format!("{} subscribers match {}", matching.len(), expression);
+    for subscriber in matching.iter().take(10) {
+        output.push_str(&format!("\n{}", subscriber.email));
+    }
+    if matching.len() > 10 {
+        output.push_str(&format!("\n... and {} more", matching.len() - 10));
+    }
+
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DAY: i64 = 24 * 60 * 60;
+
+    fn subscriber() -> Subscriber {
+        Subscriber {
+            token: String::from("token"),
+            email: String::from("example@test.com"),
+            status: String::from("subscribed"),
+            created: 100 * DAY,
+            lists: vec![String::from("weekly")],
+            tags: vec![String::from("beta")],
+            fields: [(String::from("country"), String::from("DE")), (String::from("age"), String::from("30"))].into(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn parse_segments() {
+        assert_eq!(Segment::And(
+            Box::new(Segment::And(
+                Box::new(Segment::Tag(String::from("beta"))),
+                Box::new(Segment::Compare(String::from("country"), Op::Equal, String::from("DE"))),
+            )),
+            Box::new(Segment::Not(Box::new(Segment::Within(Event::Opened, 90 * DAY)))),
+        ), Segment::parse("tag:beta AND country=DE AND NOT opened_last:90d").unwrap());
+
+        assert_eq!(Segment::Or(
+            Box::new(Segment::Tag(String::from("beta"))),
+            Box::new(Segment::And(
+                Box::new(Segment::List(String::from("weekly"))),
+                Box::new(Segment::Compare(String::from("city"), Op::NotEqual, String::from("New York"))),
+            )),
+        ), Segment::parse("tag:beta or (list:weekly and city!=\"New York\")").unwrap());
+    }
+
+    #[test]
+    fn parse_invalid_segments() {
+        for expression in ["", "tag:beta AND", "tag:beta country=DE", "(tag:beta", "tag:beta)", "opened_last:soon", "opened_last:999999999999999d", "opened_last:9é", "opened_last:é", "colour:red", "=DE", "NOT"] {
+            assert!(Segment::parse(expression).is_err(), "{:?} should be invalid", expression);
+        }
+    }
+
+    #[test]
+    fn match_segments() {
+        let subscriber = subscriber();
+        let activity = Activity { sent: Some(95 * DAY), opened: Some(20 * DAY), bounced: None };
+        let now = 101 * DAY;
+        let matches = |expression: &str| Segment::parse(expression).unwrap().matches(&subscriber, &activity, now);
+
+        assert!(matches("tag:beta AND country=DE AND NOT opened_last:30d"));
+        assert!(matches("age>=30 AND age<31 AND plan!=pro"));
+        assert!(matches("sent_last:1w AND signed_up_last:2d"));
+        assert!(!matches("tag:vip OR bounced_last:365d"));
+        assert!(!matches("plan=pro"));
+        assert!(matches("email=example@test.com"));
+    }
+}
@@ -1,53 +1,873 @@
 use std::convert::Infallible;
-use serde::Deserialize;
-use warp::{Filter, self, body::content_length_limit};
+use std::net::{IpAddr, SocketAddr};
+use std::os::unix::fs::FileTypeExt;
+use serde::{Deserialize, Serialize};
+use warp::{Filter, self, body::content_length_limit, filters::BoxedFilter, http::StatusCode};
+use tokio::net::UnixListener;
+use tokio::task::JoinSet;
+use tokio_stream::wrappers::UnixListenerStream;
+use chrono::Utc;
 use log::debug;
 
-use crate::{emails::{add_email, remove_email_with_token}, config::Config};
+use crate::{emails::{check_resubscribe_token, normalise_email, remove_email_with_token, resubscribe, signup, AddError, Added, NewSubscriber}, config::Config};
+use crate::admin;
+use crate::archive;
+use crate::bounce::{self, Bounce};
+use crate::feed;
+use crate::gdpr;
+use crate::metrics;
+use crate::newsletter::escape_html;
+use crate::preferences::{self, Outcome, Preferences};
+use crate::segment;
+use crate::spam;
+use crate::widget;
 
-#[derive(Deserialize, Clone)]
-pub struct Email {
-    pub email: String,
+/// Builds a subscriber from the signup form, which can have `email`, `name`, any number
+/// of `list` and `tag` fields and custom fields as `fields[name]`. Only custom fields
+/// declared in the config can be set from the form.
+pub fn subscriber_from_form(form: Vec<(String, String)>, config: &Config) -> NewSubscriber {
+    let mut subscriber = NewSubscriber::default();
+    for (key, value) in form {
+        match key.as_str() {
+            "email" => subscriber.email = value,
+            "name" if !value.trim().is_empty() => subscriber.name = Some(value.trim().to_string()),
+            "list" if !value.is_empty() => subscriber.lists.push(value),
+            "tag" | "tags" => subscriber.tags.extend(value.split(',')
+                                                    .map(|tag| tag.trim().to_string())
+                                                    .filter(|tag| !tag.is_empty())),
+            _ => {
+                let field = key.strip_prefix("fields[").and_then(|key| key.strip_suffix(']'));
+                if let Some(field) = field.filter(|field| config.fields.contains_key(*field)) {
+                    subscriber.fields.insert(field.to_string(), value);
+                }
+            }
+        }
+    }
+    subscriber
+}
+
+/// Only custom fields declared in the config can be set by a signup, the same as the form.
+fn declared_fields(mut subscriber: NewSubscriber, config: &Config) -> NewSubscriber {
+    subscriber.fields.retain(|field, _| config.fields.contains_key(field));
+    subscriber
+}
+
+/// The status code, result and message for a signup, used as is by the admin API.
+/// Signing up an existing subscriber is a conflict and leaves them unchanged, they can
+/// change their lists on the preference page.
+pub fn signup_result(result: &Result<Added, AddError>) -> (StatusCode, &'static str, String) {
+    match result {
+        Ok(Added::New(_)) => (StatusCode::CREATED, "subscribed", String::from("Successfully added email!")),
+        Ok(Added::Existing(_)) => (StatusCode::CONFLICT, "already_subscribed", String::from("Email already exists")),
+        Err(AddError::Invalid(err)) => (StatusCode::BAD_REQUEST, "invalid", err.clone()),
+        Err(AddError::Suppressed(err)) => (StatusCode::CONFLICT, "suppressed", err.clone()),
+        Err(AddError::Unsubscribed(err)) => (StatusCode::ACCEPTED, "confirm_resubscribe", err.clone()),
+        Err(AddError::Database(err)) => {
+            debug!("{}", err);
+            (StatusCode::INTERNAL_SERVER_ERROR, "error", String::from("The signup couldn't be saved, please try again later"))
+        },
+    }
+}
+
+/// What an anonymous signup is told. New, existing, suppressed and unsubscribed emails,
+/// and bots caught by the honeypot, all get the same reply, so the signup API can't be used to find out who's subscribed
+/// or why an email is suppressed.
+pub fn public_result(outcome: (StatusCode, &'static str, String)) -> (StatusCode, &'static str, String) {
+    match outcome.1 {
+        "subscribed" | "already_subscribed" | "suppressed" | "confirm_resubscribe" | "bot" => {
+            (StatusCode::OK, "received", String::from("Thanks for signing up! If you unsubscribed before, check your inbox for a link to confirm."))
+        },
+        _ => outcome,
+    }
+}
+
+/// The body of every signup reply, `result` is one of received, invalid, rejected,
+/// rate_limited or error.
+#[derive(Serialize)]
+pub struct SignupResponse {
+    pub status: String,
+    pub result: String,
+    pub message: String,
 }
 
-pub async fn handle_email_post(email: Email) -> Result<impl warp::Reply, Infallible> {
+fn signup_reply((status, result, message): (StatusCode, &'static str, String)) -> warp::reply::WithStatus<warp::reply::Json> {
+    let response = SignupResponse { status: status.as_str().to_string(), result: result.to_string(), message };
+    warp::reply::with_status(warp::reply::json(&response), status)
+}
+
+/// Runs the spam checks before signing up. A bot that filled in the honeypot gets the
+/// same reply as a real signup so it doesn't try again, rate limited clients get a 429
+/// and a missing or wrong form token or proof of work is `rejected`. Metrics count the
+/// signup's real result.
+async fn protected_signup(config: &Config, subscriber: NewSubscriber, client: Option<IpAddr>, proof: &spam::Proof) -> (StatusCode, &'static str, String) {
+    let outcome = match spam::check(config, client, proof, Utc::now().timestamp()) {
+        spam::Verdict::Allowed => signup_result(&signup(subscriber, false).await),
+        spam::Verdict::Bot => (StatusCode::CREATED, "bot", String::from("Successfully added email!")),
+        spam::Verdict::Limited => (StatusCode::TOO_MANY_REQUESTS, "rate_limited", String::from("Too many signups, please try again later")),
+        spam::Verdict::Rejected(err) => (StatusCode::BAD_REQUEST, "rejected", err),
+    };
+    metrics::record_signup(outcome.1);
+    public_result(outcome)
+}
+
+fn percent_encode(value: &str) -> String {
+    value.bytes().map(|byte| match byte {
+        b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (byte as char).to_string(),
+        _ => format!("%{:02X}", byte),
+    }).collect()
+}
+
+/// Adds the query parameters to a url that may already have some.
+pub fn with_query(url: &str, params: &[(&str, &str)]) -> String {
+    let query: Vec<String> = params.iter()
+        .map(|(name, value)| format!("{}={}", percent_encode(name), percent_encode(value)))
+        .collect();
+    let separator: &str = if url.contains('?') { "&" } else { "?" };
+    format!("{}{}{}", url, separator, query.join("&"))
+}
+
+/// Signups from a form are redirected to `api_redirect_signup` with the outcome as
+/// `status` and `message` query parameters, unless the request accepts JSON.
+pub async fn handle_email_post(remote: Option<SocketAddr>, forwarded: Option<String>, accept: Option<String>, form: Vec<(String, String)>) -> Result<Box<dyn warp::Reply>, Infallible> {
     debug!("handling email post request...");
     let config: Config = Config::load_config().unwrap();
-    let api: String = config.api_redirect_signup.clone(); 
-    let redirect = warp::redirect(warp::http::Uri::from_maybe_shared(api).unwrap());
-    match add_email(email.email).await {
-        Ok(_) => Ok(redirect),
-        Err(_) => Ok(redirect)
+    let client: Option<IpAddr> = spam::client_ip(remote.map(|addr| addr.ip()), forwarded.as_deref(), &config.trusted_proxies);
+    let proof = spam::Proof::from_fields(form.iter().map(|(key, value)| (key.as_str(), value.as_str())), &config);
+    let subscriber: NewSubscriber = subscriber_from_form(form, &config);
+    let outcome = protected_signup(&config, subscriber, client, &proof).await;
+
+    if accept.is_some_and(|accept| accept.contains("application/json")) {
+        return Ok(Box::new(signup_reply(outcome)));
+    }
+    let (_, status, message) = outcome;
+    let url: String = with_query(&config.api_redirect_signup, &[("status", status), ("message", message.as_str())]);
+    match warp::http::Uri::from_maybe_shared(url) {
+        Ok(uri) => Ok(Box::new(warp::redirect::see_other(uri))),
+        Err(err) => Ok(Box::new(json_reply(StatusCode::INTERNAL_SERVER_ERROR, format!("Invalid api_redirect_signup: {}", err)))),
     }
 }
 
+/// The JSON body is read as a value first so the spam protection fields can be taken
+/// out of it alongside the subscriber.
+pub async fn handle_email_json(remote: Option<SocketAddr>, forwarded: Option<String>, body: serde_json::Value) -> Result<impl warp::Reply, Infallible> {
+    debug!("handling email json request...");
+    let config: Config = Config::load_config().unwrap();
+    let client: Option<IpAddr> = spam::client_ip(remote.map(|addr| addr.ip()), forwarded.as_deref(), &config.trusted_proxies);
+    let fields = body.as_object()
+        .into_iter()
+        .flatten()
+        .filter_map(|(key, value)| value.as_str().map(|value| (key.as_str(), value)));
+    let proof = spam::Proof::from_fields(fields, &config);
+    let subscriber: NewSubscriber = match serde_json::from_value(body) {
+        Ok(subscriber) => declared_fields(subscriber, &config),
+        Err(err) => return Ok(signup_reply((StatusCode::BAD_REQUEST, "invalid", format!("Invalid signup: {}", err)))),
+    };
+    Ok(signup_reply(protected_signup(&config, subscriber, client, &proof).await))
+}
+
+#[derive(Serialize)]
+struct FormToken {
+    token: String,
+}
+
+/// A token for the signup form's `form_token` field, needed when `min_fill_time` is set.
+pub async fn handle_form_token() -> Result<impl warp::Reply, Infallible> {
+    let config: Config = Config::load_config().unwrap();
+    let token = FormToken { token: spam::form_token(&config, Utc::now().timestamp()) };
+    Ok(warp::reply::json(&token))
+}
+
+/// A proof of work challenge, needed when `pow_difficulty` is set.
+pub async fn handle_challenge() -> Result<impl warp::Reply, Infallible> {
+    let config: Config = Config::load_config().unwrap();
+    Ok(warp::reply::json(&spam::challenge(&config, Utc::now().timestamp())))
+}
+
+fn html_reply(status: StatusCode, html: String) -> warp::reply::WithStatus<warp::reply::Html<String>> {
+    warp::reply::with_status(warp::reply::html(html), status)
+}
+
+fn not_found_page() -> warp::reply::WithStatus<warp::reply::Html<String>> {
+    html_reply(StatusCode::NOT_FOUND, preferences::page("Link not found",
+                                                        "<p>This link is invalid or you have already unsubscribed.</p>"))
+}
+
+fn error_page(err: String) -> warp::reply::WithStatus<warp::reply::Html<String>> {
+    debug!("{}", err);
+    html_reply(StatusCode::INTERNAL_SERVER_ERROR, preferences::page("Something went wrong",
+                                                                    "<p>That didn't work, please try again later.</p>"))
+}
+
 pub fn remove_email_route() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone{
-    warp::path!("api" / "remove" / String)
+    let confirm = warp::path!("api" / "remove" / String)
         .and(warp::get())
-        .and_then(handle_remove_email_get)
+        .and_then(handle_remove_email_get);
+    let remove = warp::path!("api" / "remove" / String)
+        .and(warp::post())
+        .and(content_length_limit(1024 * 16))
+        .and_then(handle_remove_email_post);
+
+    confirm.or(remove)
 }
 
+/// Only shows the confirmation page, link scanners that prefetch the unsubscribe link
+/// mustn't unsubscribe anyone.
 pub async fn handle_remove_email_get(token: String) -> Result<impl warp::Reply, Infallible> {
     debug!("handling email remove request...");
     let config: Config = Config::load_config().unwrap();
-    let api: String = config.api_redirect_unsubscribe.clone(); 
-    let redirect = warp::redirect(warp::http::Uri::from_maybe_shared(api).unwrap());
+    match preferences::find(token).await {
+        Ok(Some(subscriber)) => Ok(html_reply(StatusCode::OK, preferences::render_unsubscribe(&subscriber, &config))),
+        Ok(None) => Ok(not_found_page()),
+        Err(err) => Ok(error_page(err)),
+    }
+}
+
+fn unsubscribed_page(email: &str, config: &Config) -> warp::reply::WithStatus<warp::reply::Html<String>> {
+    match preferences::render_unsubscribed(email, config) {
+        Ok(html) => html_reply(StatusCode::OK, html),
+        Err(err) => {
+            debug!("{}", err);
+            html_reply(StatusCode::OK, preferences::page("Unsubscribed", "<p>You won't receive any more newsletters.</p>"))
+        },
+    }
+}
+
+/// Unsubscribes from the confirmation page's form, or from a mail client's one-click
+/// unsubscribe (RFC 8058), which posts to the same address.
+pub async fn handle_remove_email_post(token: String) -> Result<impl warp::Reply, Infallible> {
+    debug!("handling email remove post request...");
+    let config: Config = Config::load_config().unwrap();
+    let subscriber = match preferences::find(token.clone()).await {
+        Ok(Some(subscriber)) => subscriber,
+        Ok(None) => return Ok(not_found_page()),
+        Err(err) => return Ok(error_page(err)),
+    };
     match remove_email_with_token(token).await {
-        Ok(_) => Ok(redirect),
-        Err(_) => Ok(redirect)
+        Ok(_) => Ok(unsubscribed_page(&subscriber.email, &config)),
+        Err(err) => Ok(error_page(err)),
+    }
+}
+
+#[derive(Deserialize, Clone)]
+pub struct ResubscribeQuery {
+    pub token: String,
+}
+
+fn expired_page() -> warp::reply::WithStatus<warp::reply::Html<String>> {
+    html_reply(StatusCode::NOT_FOUND, preferences::page("Link expired",
+                                                        "<p>This link is invalid or has expired, please sign up again.</p>"))
+}
+
+/// Only shows the confirmation page, the same as unsubscribing, so link scanners can't
+/// subscribe anyone.
+pub async fn handle_resubscribe_get(query: ResubscribeQuery) -> Result<impl warp::Reply, Infallible> {
+    debug!("handling resubscribe request...");
+    let config: Config = Config::load_config().unwrap();
+    match check_resubscribe_token(&config, &query.token, Utc::now().timestamp()) {
+        Some(resubscribe) => Ok(html_reply(StatusCode::OK, preferences::render_resubscribe(&resubscribe.email, &query.token))),
+        None => Ok(expired_page()),
     }
 }
 
-pub fn add_email_route() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone{
+pub async fn handle_resubscribe_post(form: ResubscribeQuery) -> Result<impl warp::Reply, Infallible> {
+    debug!("handling resubscribe post request...");
+    let config: Config = Config::load_config().unwrap();
+    let request = match check_resubscribe_token(&config, &form.token, Utc::now().timestamp()) {
+        Some(request) => request,
+        None => return Ok(expired_page()),
+    };
+    match resubscribe(&request).await {
+        Ok(_) => {
+            metrics::record_signup("subscribed");
+            Ok(html_reply(StatusCode::OK, preferences::page("Subscribed", "<p>You're subscribed again, welcome back.</p>")))
+        },
+        Err(AddError::Database(err)) => Ok(error_page(err)),
+        Err(err) => Ok(html_reply(StatusCode::CONFLICT, preferences::page("Can't subscribe", &format!("<p>{}</p>", escape_html(&err.to_string()))))),
+    }
+}
+
+/// Takes a JSON body when the content type is application/json and a form otherwise.
+pub fn add_email_route() -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone{
     debug!("constructing route...");
-    warp::path!("api" / "add")
+    let json = warp::path!("api" / "add")
+        .and(warp::post())
+        .and(warp::addr::remote())
+        .and(warp::header::optional::<String>("x-forwarded-for"))
+        .and(content_length_limit(1024 * 16))
+        .and(warp::body::json())
+        .and_then(handle_email_json);
+    let form = warp::path!("api" / "add")
         .and(warp::post())
+        .and(warp::addr::remote())
+        .and(warp::header::optional::<String>("x-forwarded-for"))
+        .and(warp::header::optional::<String>("accept"))
         .and(content_length_limit(1024 * 16))
         .and(warp::body::form())
-        .and_then(handle_email_post)
+        .and_then(handle_email_post);
+    let form_token = warp::path!("api" / "form-token")
+        .and(warp::get())
+        .and_then(handle_form_token);
+    let challenge = warp::path!("api" / "challenge")
+        .and(warp::get())
+        .and_then(handle_challenge);
+    let confirm = warp::path!("api" / "resubscribe")
+        .and(warp::get())
+        .and(warp::query::<ResubscribeQuery>())
+        .and_then(handle_resubscribe_get);
+    let resubscribe = warp::path!("api" / "resubscribe")
+        .and(warp::post())
+        .and(content_length_limit(1024 * 16))
+        .and(warp::body::form())
+        .and_then(handle_resubscribe_post);
+
+    json.or(form).or(form_token).or(challenge).or(confirm).or(resubscribe)
+}
+
+/// A bounce or complaint posted by a relay to `/api/webhook`.
+#[derive(Deserialize, Clone)]
+pub struct WebhookEvent {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub email: String,
+    pub bounce_type: Option<String>,
+    pub status: Option<String>,
+    pub token: Option<String>,
+    pub job: Option<i64>,
+    pub message_id: Option<String>,
+}
+
+/// The body of every JSON reply that isn't returning data.
+#[derive(Serialize)]
+pub struct ApiResponse {
+    pub status: String,
+    pub message: String,
 }
 
-pub fn construct_route() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
-    add_email_route()
+pub fn json_reply(status: StatusCode, message: String) -> warp::reply::WithStatus<warp::reply::Json> {
+    let response = ApiResponse { status: status.as_str().to_string(), message };
+    warp::reply::with_status(warp::reply::json(&response), status)
+}
+
+/// Compares the secrets without returning early so the time taken doesn't leak how
+/// much of the secret was right.
+fn secret_matches(given: &str, secret: &str) -> bool {
+    given.len() == secret.len()
+        && given.bytes().zip(secret.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+pub async fn handle_webhook_post(authorization: Option<String>, event: WebhookEvent) -> Result<impl warp::Reply, Infallible> {
+    debug!("handling webhook post request...");
+    let config: Config = Config::load_config().unwrap();
+
+    let authorised: bool = match (config.webhook_secret.as_deref(), authorization.as_deref()) {
+        (Some(secret), Some(authorization)) => {
+            secret_matches(authorization.strip_prefix("Bearer ").unwrap_or(authorization), secret)
+        },
+        _ => false,
+    };
+    if !authorised {
+        return Ok(json_reply(StatusCode::UNAUTHORIZED, String::from("Invalid webhook secret")));
+    }
+
+    if event.kind != "bounce" && event.kind != "complaint" {
+        return Ok(json_reply(StatusCode::BAD_REQUEST, format!("Unknown event type {}", event.kind)));
+    }
+    let email: String = match normalise_email(&event.email) {
+        Ok(email) => email,
+        Err(err) => return Ok(json_reply(StatusCode::BAD_REQUEST, err)),
+    };
+    let hard: bool = match event.bounce_type.as_deref() {
+        None | Some("hard") => true,
+        Some("soft") => false,
+        Some(other) => return Ok(json_reply(StatusCode::BAD_REQUEST, format!("Unknown bounce type {}", other))),
+    };
+
+    let bounce = Bounce {
+        email,
+        token: event.token,
+        job: event.job,
+        hard,
+        status: event.status.unwrap_or_default(),
+        message_id: event.message_id,
+    };
+    match bounce::record_event(&event.kind, bounce).await {
+        Ok(output) => Ok(json_reply(StatusCode::OK, output)),
+        Err(err) => Ok(json_reply(StatusCode::INTERNAL_SERVER_ERROR, err)),
+    }
+}
+
+pub fn webhook_route() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone{
+    warp::path!("api" / "webhook")
+        .and(warp::post())
+        .and(warp::header::optional::<String>("authorization"))
+        .and(content_length_limit(1024 * 16))
+        .and(warp::body::json())
+        .and_then(handle_webhook_post)
+}
+
+/// Checks the bearer token against the configured `api_tokens`, with none configured
+/// every request is refused.
+pub fn bearer_authorised(authorization: Option<&str>, tokens: &[String]) -> bool {
+    match authorization.and_then(|authorization| authorization.strip_prefix("Bearer ")) {
+        Some(given) => tokens.iter().any(|token| secret_matches(given, token)),
+        None => false,
+    }
+}
+
+#[derive(Deserialize, Clone)]
+pub struct EmailQuery {
+    pub email: String,
+}
+
+pub async fn handle_gdpr_get(authorization: Option<String>, query: EmailQuery) -> Result<impl warp::Reply, Infallible> {
+    debug!("handling gdpr export request...");
+    let config: Config = Config::load_config().unwrap();
+    if !bearer_authorised(authorization.as_deref(), &config.api_tokens) {
+        return Ok(json_reply(StatusCode::UNAUTHORIZED, String::from("Invalid API token")));
+    }
+    if let Err(err) = normalise_email(&query.email) {
+        return Ok(json_reply(StatusCode::BAD_REQUEST, err));
+    }
+
+    match gdpr::collect(query.email).await {
+        Ok(data) if data.is_empty() => Ok(json_reply(StatusCode::NOT_FOUND, format!("Nothing is stored about {}", data.email))),
+        Ok(data) => Ok(warp::reply::with_status(warp::reply::json(&data), StatusCode::OK)),
+        Err(err) => Ok(json_reply(StatusCode::INTERNAL_SERVER_ERROR, err)),
+    }
+}
+
+pub async fn handle_gdpr_delete(authorization: Option<String>, query: EmailQuery) -> Result<impl warp::Reply, Infallible> {
+    debug!("handling gdpr erasure request...");
+    let config: Config = Config::load_config().unwrap();
+    if !bearer_authorised(authorization.as_deref(), &config.api_tokens) {
+        return Ok(json_reply(StatusCode::UNAUTHORIZED, String::from("Invalid API token")));
+    }
+    let email: String = match normalise_email(&query.email) {
+        Ok(email) => email,
+        Err(err) => return Ok(json_reply(StatusCode::BAD_REQUEST, err)),
+    };
+
+    match gdpr::collect(email.clone()).await {
+        Ok(data) if data.is_empty() => return Ok(json_reply(StatusCode::NOT_FOUND, format!("Nothing is stored about {}", email))),
+        Ok(_) => (),
+        Err(err) => return Ok(json_reply(StatusCode::INTERNAL_SERVER_ERROR, err)),
+    }
+    match gdpr::erase(email).await {
+        Ok(output) => Ok(json_reply(StatusCode::OK, output)),
+        Err(err) => Ok(json_reply(StatusCode::INTERNAL_SERVER_ERROR, err)),
+    }
+}
+
+pub fn gdpr_route() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone{
+    let export = warp::path!("api" / "gdpr")
+        .and(warp::get())
+        .and(warp::header::optional::<String>("authorization"))
+        .and(warp::query::<EmailQuery>())
+        .and_then(handle_gdpr_get);
+    let erase = warp::path!("api" / "gdpr")
+        .and(warp::delete())
+        .and(warp::header::optional::<String>("authorization"))
+        .and(warp::query::<EmailQuery>())
+        .and_then(handle_gdpr_delete);
+
+    export.or(erase)
+}
+
+/// Reads the preference form, which has `name`, `locale`, `frequency`, a `list` field for
+/// every list that's ticked, an optional `reason` and `action` set to `save` or
+/// `unsubscribe`.
+pub fn preferences_from_form(form: Vec<(String, String)>) -> Preferences {
+    let mut preferences = Preferences { frequency: String::from(preferences::ALL), ..Default::default() };
+    for (key, value) in form {
+        let trimmed: &str = value.trim();
+        match key.as_str() {
+            "name" if !trimmed.is_empty() => preferences.name = Some(trimmed.to_string()),
+            "locale" if !trimmed.is_empty() => preferences.locale = Some(trimmed.to_string()),
+            "frequency" => preferences.frequency = trimmed.to_string(),
+            "list" if !value.is_empty() => preferences.lists.push(value),
+            "reason" if !trimmed.is_empty() => preferences.reason = Some(trimmed.to_string()),
+            "action" => preferences.unsubscribe = value == "unsubscribe",
+            _ => (),
+        }
+    }
+    preferences
+}
+
+pub async fn handle_preferences_get(token: String) -> Result<impl warp::Reply, Infallible> {
+    debug!("handling preferences request...");
+    let config: Config = Config::load_config().unwrap();
+    match preferences::find(token).await {
+        Ok(Some(subscriber)) => Ok(html_reply(StatusCode::OK, preferences::render(&subscriber, &config, None))),
+        Ok(None) => Ok(not_found_page()),
+        Err(err) => Ok(error_page(err)),
+    }
+}
+
+pub async fn handle_preferences_post(token: String, form: Vec<(String, String)>) -> Result<impl warp::Reply, Infallible> {
+    debug!("handling preferences post request...");
+    let config: Config = Config::load_config().unwrap();
+    match preferences::update(token, preferences_from_form(form)).await {
+        Ok(Outcome::Saved(subscriber)) => {
+            Ok(html_reply(StatusCode::OK, preferences::render(&subscriber, &config, Some("Your preferences have been saved."))))
+        },
+        Ok(Outcome::Unsubscribed(email)) => Ok(unsubscribed_page(&email, &config)),
+        Ok(Outcome::Invalid(subscriber, err)) => {
+            Ok(html_reply(StatusCode::BAD_REQUEST, preferences::render(&subscriber, &config, Some(err.as_str()))))
+        },
+        Ok(Outcome::NotFound) => Ok(not_found_page()),
+        Err(err) => Ok(error_page(err)),
+    }
+}
+
+pub fn preferences_route() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone{
+    let show = warp::path!("preferences" / String)
+        .and(warp::get())
+        .and_then(handle_preferences_get);
+    let update = warp::path!("preferences" / String)
+        .and(warp::post())
+        .and(content_length_limit(1024 * 16))
+        .and(warp::body::form())
+        .and_then(handle_preferences_post);
+
+    show.or(update)
+}
+
+/// A transparent 1x1 gif returned for the open tracking image.
+const PIXEL: &[u8] = &[
+    0x47, 0x49, 0x46, 0x38, 0x39, 0x61, 0x01, 0x00, 0x01, 0x00, 0x80, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0xff, 0xff, 0xff, 0x21, 0xf9, 0x04, 0x01, 0x00, 0x00, 0x00, 0x00, 0x2c, 0x00, 0x00, 0x00, 0x00,
+    0x01, 0x00, 0x01, 0x00, 0x00, 0x02, 0x02, 0x44, 0x01, 0x00, 0x3b,
+];
+
+pub async fn handle_open_get(token: String, job: i64) -> Result<impl warp::Reply, Infallible> {
+    debug!("handling open request...");
+    if let Err(err) = segment::record_open(token, job).await {
+        debug!("{}", err);
+    }
+    let reply = warp::reply::with_header(PIXEL, "content-type", "image/gif");
+    Ok(warp::reply::with_header(reply, "cache-control", "no-store"))
+}
+
+pub fn open_route() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone{
+    warp::path!("api" / "open" / String / i64)
+        .and(warp::get())
+        .and_then(handle_open_get)
+}
+
+/// An origin such as `https://example.com`, without a path. Trailing slashes are dropped.
+pub fn cors_origin(origin: &str) -> Result<String, String> {
+    let uri: warp::http::Uri = match origin.trim_end_matches('/').parse() {
+        Ok(uri) => uri,
+        Err(_) => return Err(format!("Invalid CORS origin {}", origin)),
+    };
+    match (uri.scheme_str(), uri.authority()) {
+        (Some(scheme), Some(authority)) if uri.path() == "/" || uri.path().is_empty() => Ok(format!("{}://{}", scheme, authority)),
+        _ => Err(format!("Invalid CORS origin {}, expected a scheme and host like https://example.com", origin)),
+    }
+}
+
+/// The origins allowed to call the signup API from a browser, `cors_origins` and the
+/// server's own origin from `api_endpoint`, which the embedded signup page posts from.
+/// None when `cors_origins` is empty and CORS is off.
+pub fn cors_origins(config: &Config) -> Result<Option<Vec<String>>, String> {
+    if config.cors_origins.is_empty() {
+        return Ok(None);
+    }
+    let mut origins: Vec<String> = config.cors_origins.iter()
+        .filter(|origin| origin.as_str() != "*")
+        .map(|origin| cors_origin(origin))
+        .collect::<Result<Vec<String>, String>>()?;
+    if config.cors_origins.iter().any(|origin| origin == "*") {
+        return Ok(Some(Vec::new()));
+    }
+    origins.push(cors_origin(&config.api_endpoint)?);
+    Ok(Some(origins))
+}
+
+/// The signup routes, answering cross-origin requests when `cors_origins` is set. Only
+/// these routes use CORS, the preference and unsubscribe pages post to themselves.
+pub fn signup_route(config: &Config) -> Result<BoxedFilter<(Box<dyn warp::Reply>,)>, String> {
+    let route = add_email_route();
+    let origins: Vec<String> = match cors_origins(config)? {
+        Some(origins) => origins,
+        None => return Ok(route.map(|reply| Box::new(reply) as Box<dyn warp::Reply>).boxed()),
+    };
+    let cors = warp::cors()
+        .allow_methods(vec!["GET", "POST"])
+        .allow_headers(vec!["content-type", "accept"])
+        .max_age(3600);
+    // an empty list means `*` was configured
+    let cors = if origins.is_empty() {
+        cors.allow_any_origin()
+    } else {
+        cors.allow_origins(origins.iter().map(|origin| origin.as_str()))
+    };
+    Ok(route.with(cors).map(|reply| Box::new(reply) as Box<dyn warp::Reply>).boxed())
+}
+
+pub fn widget_route() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone{
+    let script = warp::path!("embed" / "signup.js")
+        .and(warp::get())
+        .and_then(handle_widget_script);
+    let page = warp::path!("embed" / "signup")
+        .and(warp::get())
+        .and_then(handle_widget_page);
+
+    script.or(page)
+}
+
+pub async fn handle_widget_script() -> Result<impl warp::Reply, Infallible> {
+    let config: Config = Config::load_config().unwrap();
+    Ok(warp::reply::with_header(widget::script(&config), "content-type", "application/javascript; charset=utf-8"))
+}
+
+pub async fn handle_widget_page() -> Result<impl warp::Reply, Infallible> {
+    Ok(warp::reply::html(widget::page()))
+}
+
+pub fn construct_route(config: &Config) -> Result<impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone, String> {
+    Ok(signup_route(config)?
         .or(remove_email_route())
+        .or(webhook_route())
+        .or(gdpr_route())
+        .or(open_route())
+        .or(preferences_route())
+        .or(widget_route())
+        .or(archive::archive_route())
+        .or(feed::feed_route())
+        .or(metrics::metrics_route())
+        .or(admin::admin_route())
+        .with(warp::log::custom(metrics::record_request)))
+}
+
+/// Every address in `listen` on the configured port, addresses are IPv4 or IPv6.
+pub fn listen_addresses(config: &Config) -> Result<Vec<SocketAddr>, String> {
+    config.listen.iter()
+        .map(|address| match address.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>() {
+            Ok(ip) => Ok(SocketAddr::new(ip, config.port)),
+            Err(_) => Err(format!("Invalid listen address {}, expected an IP address", address)),
+        })
+        .collect()
+}
+
+/// The certificate and key to serve HTTPS with, they have to be set together.
+pub fn tls_files(config: &Config) -> Result<Option<(String, String)>, String> {
+    match (&config.tls_cert, &config.tls_key) {
+        (Some(cert), Some(key)) => Ok(Some((cert.clone(), key.clone()))),
+        (None, None) => Ok(None),
+        _ => Err(String::from("tls_cert and tls_key must be set together")),
+    }
+}
+
+/// Serves the routes on every listen address, over TLS when a certificate is configured,
+/// and on the unix socket when one is set, until one of them stops. Every address is
+/// bound before any is served, so one that can't be used is an error rather than a
+/// panic in its task.
+pub async fn serve(config: &Config) -> Result<(), String> {
+    let addresses: Vec<SocketAddr> = listen_addresses(config)?;
+    let tls: Option<(String, String)> = tls_files(config)?;
+    let routes = construct_route(config)?;
+
+    let mut servers: JoinSet<()> = JoinSet::new();
+    for address in addresses {
+        debug!("listening on {}{}", address, if tls.is_some() { " with tls" } else { "" });
+        match &tls {
+            Some((cert, key)) => {
+                let bound = warp::serve(routes.clone())
+                    .tls()
+                    .cert_path(cert)
+                    .key_path(key)
+                    .try_bind_with_graceful_shutdown(address, std::future::pending());
+                match bound {
+                    Ok((_, server)) => {
+                        servers.spawn(server);
+                    },
+                    Err(err) => return Err(format!("Cannot listen on {}: {}", address, err)),
+                }
+            },
+            None => match warp::serve(routes.clone()).try_bind_ephemeral(address) {
+                Ok((_, server)) => {
+                    servers.spawn(server);
+                },
+                Err(err) => return Err(format!("Cannot listen on {}: {}", address, err)),
+            },
+        }
+    }
+    if let Some(path) = &config.unix_socket {
+        debug!("listening on {}", path);
+        // a socket left behind by a previous run stops the bind, anything else at the
+        // path is left alone
+        match std::fs::symlink_metadata(path) {
+            Ok(metadata) if metadata.file_type().is_socket() => {
+                if let Err(err) = std::fs::remove_file(path) {
+                    return Err(format!("Cannot remove old socket {}: {}", path, err));
+                }
+            },
+            Ok(_) => return Err(format!("Cannot listen on {}, it exists and isn't a socket", path)),
+            Err(_) => (),
+        }
+        let listener: UnixListener = match UnixListener::bind(path) {
+            Ok(listener) => listener,
+            Err(err) => return Err(format!("Cannot listen on {}: {}", path, err)),
+        };
+        servers.spawn(warp::serve(routes.clone()).run_incoming(UnixListenerStream::new(listener)));
+    }
+    if servers.is_empty() {
+        return Err(String::from("Nothing to listen on, set listen or unix_socket"));
+    }
+
+    match servers.join_next().await {
+        Some(Err(err)) => Err(format!("Warp server stopped: {}", err)),
+        _ => Err(String::from("Warp server stopped")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use warp::http::StatusCode;
+
+    use super::{bearer_authorised, cors_origin, cors_origins, listen_addresses, preferences_from_form, public_result, secret_matches, signup_result, subscriber_from_form, tls_files, with_query};
+    use crate::emails::{AddError, Added};
+    use crate::preferences::Preferences;
+    use crate::config::Config;
+
+    #[test]
+    fn compare_secrets() {
+        assert!(secret_matches("s3cret", "s3cret"));
+        assert!(!secret_matches("s3cres", "s3cret"));
+        assert!(!secret_matches("s3cre", "s3cret"));
+        assert!(!secret_matches("", "s3cret"));
+    }
+
+    #[test]
+    fn read_signup_form() {
+        let mut config = Config::default();
+        config.fields.insert(String::from("country"), String::from("text"));
+        let form = vec![
+            (String::from("email"), String::from("example@test.com")),
+            (String::from("name"), String::from(" Example ")),
+            (String::from("list"), String::from("weekly")),
+            (String::from("tag"), String::from("beta, web")),
+            (String::from("fields[country]"), String::from("DE")),
+            (String::from("fields[plan]"), String::from("pro")),
+        ];
+
+        let subscriber = subscriber_from_form(form, &config);
+        assert_eq!("example@test.com", subscriber.email);
+        assert_eq!(Some(String::from("Example")), subscriber.name);
+        assert_eq!(vec![String::from("weekly")], subscriber.lists);
+        assert_eq!(vec![String::from("beta"), String::from("web")], subscriber.tags);
+        assert_eq!(vec![(&String::from("country"), &String::from("DE"))], subscriber.fields.iter().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn check_bearer_tokens() {
+        let tokens = vec![String::from("first"), String::from("second")];
+
+        assert!(bearer_authorised(Some("Bearer second"), &tokens));
+        assert!(!bearer_authorised(Some("second"), &tokens));
+        assert!(!bearer_authorised(Some("Bearer third"), &tokens));
+        assert!(!bearer_authorised(None, &tokens));
+        assert!(!bearer_authorised(Some("Bearer "), &[]));
+    }
+
+    #[test]
+    fn read_preference_form() {
+        let form = vec![
+            (String::from("name"), String::from(" ")),
+            (String::from("locale"), String::from("de")),
+            (String::from("frequency"), String::from("weekly")),
+            (String::from("list"), String::from("weekly")),
+            (String::from("list"), String::from("monthly")),
+            (String::from("reason"), String::from("Too many emails ")),
+            (String::from("action"), String::from("unsubscribe")),
+        ];
+
+        assert_eq!(Preferences {
+            name: None,
+            locale: Some(String::from("de")),
+            frequency: String::from("weekly"),
+            lists: vec![String::from("weekly"), String::from("monthly")],
+            unsubscribe: true,
+            reason: Some(String::from("Too many emails")),
+        }, preferences_from_form(form));
+        assert_eq!("all", preferences_from_form(Vec::new()).frequency);
+    }
+
+    #[test]
+    fn signup_status_codes() {
+        let code = |result| {
+            let (status, result, _) = signup_result(&result);
+            (status, result)
+        };
+
+        assert_eq!((StatusCode::CREATED, "subscribed"), code(Ok(Added::New(String::from("1")))));
+        assert_eq!((StatusCode::CONFLICT, "already_subscribed"), code(Ok(Added::Existing(String::from("1")))));
+        assert_eq!((StatusCode::BAD_REQUEST, "invalid"), code(Err(AddError::Invalid(String::from("Email is invalid")))));
+        assert_eq!((StatusCode::CONFLICT, "suppressed"), code(Err(AddError::Suppressed(String::from("blocked")))));
+        assert_eq!((StatusCode::ACCEPTED, "confirm_resubscribe"), code(Err(AddError::Unsubscribed(String::from("unsubscribed")))));
+        assert_eq!((StatusCode::INTERNAL_SERVER_ERROR, "error"), code(Err(AddError::Database(String::from("down")))));
+    }
+
+    #[test]
+    fn public_signup_replies() {
+        let public = |result| public_result(signup_result(&result));
+        let received = public(Ok(Added::New(String::from("1"))));
+
+        assert_eq!((StatusCode::OK, "received"), (received.0, received.1));
+        assert_eq!(received, public(Ok(Added::Existing(String::from("1")))));
+        assert_eq!(received, public(Err(AddError::Suppressed(String::from("Email is on the suppression list (bounced)")))));
+        assert_eq!(received, public(Err(AddError::Unsubscribed(String::from("unsubscribed")))));
+        assert_eq!(received, public_result((StatusCode::CREATED, "bot", String::new())));
+        assert_eq!((StatusCode::BAD_REQUEST, "invalid"), {
+            let (status, result, _) = public(Err(AddError::Invalid(String::from("Email is invalid"))));
+            (status, result)
+        });
+    }
+
+    #[test]
+    fn redirect_with_query() {
+        assert_eq!("https://example.com/?status=invalid&message=Email%20is%20invalid",
+                   with_query("https://example.com/", &[("status", "invalid"), ("message", "Email is invalid")]));
+        assert_eq!("https://example.com/?ref=form&status=subscribed",
+                   with_query("https://example.com/?ref=form", &[("status", "subscribed")]));
+    }
+
+    #[test]
+    fn read_listen_config() {
+        let mut config = Config {
+            listen: vec![String::from("0.0.0.0"), String::from("[::1]")],
+            port: 8080,
+            ..Default::default()
+        };
+
+        let addresses: Vec<SocketAddr> = vec!["0.0.0.0:8080".parse().unwrap(), "[::1]:8080".parse().unwrap()];
+        assert_eq!(addresses, listen_addresses(&config).unwrap());
+        config.listen.push(String::from("localhost"));
+        assert!(listen_addresses(&config).is_err());
+
+        assert_eq!(Ok(None), tls_files(&config));
+        config.tls_cert = Some(String::from("/etc/newsman/cert.pem"));
+        assert!(tls_files(&config).is_err());
+        config.tls_key = Some(String::from("/etc/newsman/key.pem"));
+        assert_eq!(Ok(Some((String::from("/etc/newsman/cert.pem"), String::from("/etc/newsman/key.pem")))), tls_files(&config));
+    }
+
+    #[test]
+    fn read_cors_config() {
+        let mut config = Config { api_endpoint: String::from("https://news.example.com/"), ..Default::default() };
+        assert_eq!(Ok(None), cors_origins(&config));
+
+        config.cors_origins = vec![String::from("https://www.example.com/"), String::from("http://localhost:8000")];
+        assert_eq!(Ok(Some(vec![String::from("https://www.example.com"),
+                                String::from("http://localhost:8000"),
+                                String::from("https://news.example.com")])), cors_origins(&config));
+        config.cors_origins.push(String::from("*"));
+        assert_eq!(Ok(Some(Vec::new())), cors_origins(&config));
+
+        assert!(cors_origin("www.example.com").is_err());
+        assert!(cors_origin("https://www.example.com/signup").is_err());
+        assert_eq!(Ok(String::from("http://127.0.0.1:3600")), cors_origin(&Config::default().api_endpoint));
+    }
 }
@@ -0,0 +1,318 @@
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::sync::{Mutex, OnceLock};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+use log::debug;
+
+use crate::Config;
+
+/// How long a proof of work challenge can be solved for.
+const CHALLENGE_LIFETIME: i64 = 10 * 60;
+/// How long a form token stays valid, so a page left open still works the next day.
+const FORM_TOKEN_LIFETIME: i64 = 24 * 60 * 60;
+
+/// Signup times by client address, for rate limiting.
+static SIGNUPS: Mutex<BTreeMap<IpAddr, Vec<i64>>> = Mutex::new(BTreeMap::new());
+/// Challenges that have been solved along with when they expire, so each is only used once.
+static SOLVED: Mutex<BTreeMap<String, i64>> = Mutex::new(BTreeMap::new());
+/// Used to sign tokens and challenges when the config doesn't set a signup_secret.
+static SECRET: OnceLock<String> = OnceLock::new();
+
+fn secret(config: &Config) -> String {
+    match &config.signup_secret {
+        Some(secret) => secret.clone(),
+        None => SECRET.get_or_init(|| Uuid::new_v4().to_string()).clone(),
+    }
+}
+
+/// HMAC-SHA256 (RFC 2104) of the message, as hex.
+pub fn sign(secret: &str, message: &str) -> String {
+    let mut key: Vec<u8> = secret.as_bytes().to_vec();
+    if key.len() > 64 {
+        key = Sha256::digest(&key).to_vec();
+    }
+    key.resize(64, 0);
+
+    let inner_pad: Vec<u8> = key.iter().map(|byte| byte ^ 0x36).collect();
+    let outer_pad: Vec<u8> = key.iter().map(|byte| byte ^ 0x5c).collect();
+    let inner = Sha256::new().chain_update(&inner_pad).chain_update(message.as_bytes()).finalize();
+    let outer = Sha256::new().chain_update(&outer_pad).chain_update(inner).finalize();
+    format!("{:x}", outer)
+}
+
+/// Splits a signed `payload.signature` value, returning the payload if the signature
+/// is right.
+fn verify<'a>(secret: &str, signed: &'a str) -> Option<&'a str> {
+    let (payload, signature) = signed.rsplit_once('.')?;
+    let expected: String = sign(secret, payload);
+    let matches: bool = expected.len() == signature.len()
+        && expected.bytes().zip(signature.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0;
+    matches.then_some(payload)
+}
+
+/// Signs a payload with the signup secret, for values that have to come back unchanged.
+pub fn sign_payload(config: &Config, payload: &str) -> String {
+    format!("{}.{}", payload, sign(&secret(config), payload))
+}
+
+/// The payload of a value made by sign_payload, if its signature is right.
+pub fn verify_payload(config: &Config, signed: &str) -> Option<String> {
+    verify(&secret(config), signed).map(|payload| payload.to_string())
+}
+
+/// The address a request came from. `X-Forwarded-For` is only believed when the
+/// connection is from a trusted proxy, or over the unix socket, and then the last
+/// address in it that isn't a trusted proxy is the client.
+pub fn client_ip(remote: Option<IpAddr>, forwarded: Option<&str>, trusted_proxies: &[String]) -> Option<IpAddr> {
+    let trusted = |ip: &IpAddr| trusted_proxies.iter().any(|proxy| proxy.parse::<IpAddr>().is_ok_and(|proxy| proxy == *ip));
+    if remote.as_ref().is_some_and(|remote| !trusted(remote)) {
+        return remote;
+    }
+
+    let forwarded: Vec<IpAddr> = forwarded.unwrap_or_default()
+        .split(',')
+        .filter_map(|ip| ip.trim().parse::<IpAddr>().ok())
+        .collect();
+    forwarded.iter()
+        .rev()
+        .find(|ip| !trusted(ip))
+        .or(forwarded.first())
+        .copied()
+        .or(remote)
+}
+
+/// Records a signup from the address, returning false once it has made `limit` signups
+/// within the last `window` seconds.
+fn allow(signups: &mut BTreeMap<IpAddr, Vec<i64>>, ip: IpAddr, now: i64, limit: u32, window: i64) -> bool {
+    signups.retain(|_, times| {
+        times.retain(|time| *time > now - window);
+        !times.is_empty()
+    });
+    let times: &mut Vec<i64> = signups.entry(ip).or_default();
+    if times.len() >= limit as usize {
+        return false;
+    }
+    times.push(now);
+    true
+}
+
+/// A form token holding when the form was served, for checking it isn't submitted
+/// faster than a person could fill it in.
+pub fn form_token(config: &Config, now: i64) -> String {
+    let payload: String = now.to_string();
+    format!("{}.{}", payload, sign(&secret(config), &payload))
+}
+
+fn check_form_token(secret: &str, token: Option<&str>, min_fill_time: i64, now: i64) -> Result<(), String> {
+    let issued: i64 = match token.and_then(|token| verify(secret, token)).and_then(|payload| payload.parse::<i64>().ok()) {
+        Some(issued) => issued,
+        None => return Err(String::from("The form token is missing or invalid, reload the page and try again")),
+    };
+    if now - issued < min_fill_time {
+        return Err(String::from("The form was sent too quickly, please try again"));
+    }
+    if now - issued > FORM_TOKEN_LIFETIME {
+        return Err(String::from("The form has expired, reload the page and try again"));
+    }
+    Ok(())
+}
+
+/// A proof of work challenge, a client has to find a nonce where the SHA-256 of the
+/// challenge followed by the nonce starts with `difficulty` zero bits.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Challenge {
+    pub challenge: String,
+    pub difficulty: u32,
+}
+
+pub fn challenge(config: &Config, now: i64) -> Challenge {
+    let payload: String = format!("{}-{}", now, Uuid::new_v4().simple());
+    Challenge {
+        challenge: format!("{}.{}", payload, sign(&secret(config), &payload)),
+        difficulty: config.pow_difficulty,
+    }
+}
+
+fn leading_zero_bits(hash: &[u8]) -> u32 {
+    let mut bits: u32 = 0;
+    for byte in hash {
+        bits += byte.leading_zeros();
+        if *byte != 0 {
+            break;
+        }
+    }
+    bits
+}
+
+/// Whether the nonce solves the challenge, without checking its signature.
+pub fn solves(challenge: &str, nonce: &str, difficulty: u32) -> bool {
+    let hash = Sha256::new().chain_update(challenge.as_bytes()).chain_update(nonce.as_bytes()).finalize();
+    leading_zero_bits(&hash) >= difficulty
+}
+
+fn check_challenge(secret: &str, solved: &mut BTreeMap<String, i64>, proof: &Proof, difficulty: u32, now: i64) -> Result<(), String> {
+    let (challenge, nonce) = match (&proof.challenge, &proof.nonce) {
+        (Some(challenge), Some(nonce)) => (challenge, nonce),
+        _ => return Err(String::from("The proof of work is missing")),
+    };
+    let issued: Option<i64> = verify(secret, challenge)
+        .and_then(|payload| payload.split_once('-'))
+        .and_then(|(issued, _)| issued.parse::<i64>().ok());
+    match issued {
+        Some(issued) if now - issued <= CHALLENGE_LIFETIME => (),
+        Some(_) => return Err(String::from("The proof of work challenge has expired, please try again")),
+        None => return Err(String::from("The proof of work challenge is invalid")),
+    }
+    if !solves(challenge, nonce, difficulty) {
+        return Err(String::from("The proof of work is wrong"));
+    }
+
+    solved.retain(|_, expires| *expires > now);
+    if solved.insert(challenge.clone(), now + CHALLENGE_LIFETIME).is_some() {
+        return Err(String::from("The proof of work challenge has already been used"));
+    }
+    Ok(())
+}
+
+/// What a signup sent along to show it came from a person.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Proof {
+    pub honeypot: bool,
+    pub form_token: Option<String>,
+    pub challenge: Option<String>,
+    pub nonce: Option<String>,
+}
+
+impl Proof {
+    /// Reads the `form_token`, `pow_challenge` and `pow_nonce` fields, and whether the
+    /// configured honeypot field was filled in.
+    pub fn from_fields<'a>(fields: impl Iterator<Item = (&'a str, &'a str)>, config: &Config) -> Proof {
+        let mut proof = Proof::default();
+        for (key, value) in fields {
+            match key {
+                "form_token" => proof.form_token = Some(value.to_string()),
+                "pow_challenge" => proof.challenge = Some(value.to_string()),
+                "pow_nonce" => proof.nonce = Some(value.to_string()),
+                _ if config.honeypot_field.as_deref() == Some(key) => proof.honeypot |= !value.trim().is_empty(),
+                _ => (),
+            }
+        }
+        proof
+    }
+}
+
+/// Whether a signup should go ahead.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Verdict {
+    Allowed,
+    Bot,
+    Limited,
+    Rejected(String),
+}
+
+/// Checks a signup against every protection that's turned on in the config, the
+/// honeypot, the rate limit, the form fill time and the proof of work, in that order.
+pub fn check(config: &Config, client: Option<IpAddr>, proof: &Proof, now: i64) -> Verdict {
+    if proof.honeypot {
+        debug!("honeypot filled in by {:?}", client);
+        return Verdict::Bot;
+    }
+    if let (Some(ip), true) = (client, config.signup_rate_limit > 0) {
+        let mut signups = SIGNUPS.lock().unwrap();
+        if !allow(&mut signups, ip, now, config.signup_rate_limit, config.signup_rate_window) {
+            debug!("rate limiting signups from {}", ip);
+            return Verdict::Limited;
+        }
+    }
+
+    let secret: String = secret(config);
+    if config.min_fill_time > 0 {
+        if let Err(err) = check_form_token(&secret, proof.form_token.as_deref(), config.min_fill_time, now) {
+            return Verdict::Rejected(err);
+        }
+    }
+    if config.pow_difficulty > 0 {
+        let mut solved = SOLVED.lock().unwrap();
+        if let Err(err) = check_challenge(&secret, &mut solved, proof, config.pow_difficulty, now) {
+            return Verdict::Rejected(err);
+        }
+    }
+
+    Verdict::Allowed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hmac_test_vector() {
+        // RFC 4231 test case 2
+        assert_eq!("5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843", sign("Jefe", "what do ya want for nothing?"));
+    }
+
+    #[test]
+    fn find_client_ip() {
+        let trusted = vec![String::from("10.0.0.1")];
+        let proxy: IpAddr = "10.0.0.1".parse().unwrap();
+        let client: IpAddr = "203.0.113.7".parse().unwrap();
+
+        assert_eq!(Some(client), client_ip(Some(client), Some("198.51.100.1"), &trusted));
+        assert_eq!(Some(client), client_ip(Some(proxy), Some("198.51.100.1, 203.0.113.7"), &trusted));
+        assert_eq!(Some(client), client_ip(Some(proxy), Some("203.0.113.7, 10.0.0.1"), &trusted));
+        assert_eq!(Some(proxy), client_ip(Some(proxy), None, &trusted));
+        assert_eq!(Some(client), client_ip(None, Some("203.0.113.7"), &[]));
+    }
+
+    #[test]
+    fn rate_limit_signups() {
+        let mut signups = BTreeMap::new();
+        let ip: IpAddr = "203.0.113.7".parse().unwrap();
+
+        assert!(allow(&mut signups, ip, 100, 2, 60));
+        assert!(allow(&mut signups, ip, 110, 2, 60));
+        assert!(!allow(&mut signups, ip, 120, 2, 60));
+        assert!(allow(&mut signups, ip, 161, 2, 60));
+    }
+
+    #[test]
+    fn check_form_fill_time() {
+        let config = Config { signup_secret: Some(String::from("secret")), ..Default::default() };
+        let token = form_token(&config, 1000);
+
+        assert!(check_form_token("secret", Some(&token), 5, 1010).is_ok());
+        assert!(check_form_token("secret", Some(&token), 5, 1002).is_err());
+        assert!(check_form_token("secret", Some(&token), 5, 1000 + FORM_TOKEN_LIFETIME + 1).is_err());
+        assert!(check_form_token("other", Some(&token), 5, 1010).is_err());
+        assert!(check_form_token("secret", Some("1000.forged"), 5, 1010).is_err());
+        assert!(check_form_token("secret", None, 5, 1010).is_err());
+    }
+
+    #[test]
+    fn check_proof_of_work() {
+        let config = Config { signup_secret: Some(String::from("secret")), pow_difficulty: 8, ..Default::default() };
+        let challenge = challenge(&config, 1000).challenge;
+        let nonce = (0..).map(|nonce: u64| nonce.to_string()).find(|nonce| solves(&challenge, nonce, 8)).unwrap();
+        let proof = Proof { challenge: Some(challenge), nonce: Some(nonce), ..Default::default() };
+        let wrong = (0..).map(|nonce: u64| nonce.to_string()).find(|nonce| !solves(proof.challenge.as_deref().unwrap(), nonce, 8)).unwrap();
+        let mut solved = BTreeMap::new();
+
+        assert!(check_challenge("secret", &mut solved, &proof, 8, 1000 + CHALLENGE_LIFETIME + 1).is_err());
+        assert!(check_challenge("secret", &mut solved, &Proof { nonce: Some(wrong), ..proof.clone() }, 8, 1010).is_err());
+        assert_eq!(Ok(()), check_challenge("secret", &mut solved, &proof, 8, 1010));
+        assert!(check_challenge("secret", &mut solved, &proof, 8, 1020).is_err());
+    }
+
+    #[test]
+    fn read_proof_fields() {
+        let config = Config { honeypot_field: Some(String::from("website")), ..Default::default() };
+        let fields = [("email", "example@test.com"), ("website", "http://spam"), ("form_token", "1.abc")];
+
+        let proof = Proof::from_fields(fields.into_iter(), &config);
+        assert!(proof.honeypot);
+        assert_eq!(Some(String::from("1.abc")), proof.form_token);
+        assert!(!Proof::from_fields([("website", " ")].into_iter(), &config).honeypot);
+    }
+}
@@ -0,0 +1,352 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use serde::Serialize;
+use sqlx::mysql::{MySqlPool, MySqlPoolOptions};
+use chrono::{TimeZone, Utc};
+use log::debug;
+
+use crate::Config;
+use crate::newsletter::parse_time;
+
+/// A subscriber with everything stored against them.
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+pub struct Subscriber {
+    pub token: String,
+    pub email: String,
+    pub name: Option<String>,
+    pub status: String,
+    pub created: i64,
+    pub locale: Option<String>,
+    pub frequency: String,
+    pub lists: Vec<String>,
+    pub tags: Vec<String>,
+    pub fields: BTreeMap<String, String>,
+}
+
+impl Subscriber {
+    /// The values newsletters can use as `{{ placeholders }}`, every custom field by its
+    /// name along with `email`, `name`, `token`, `locale` and `tags`.
+    pub fn template_values(&self) -> BTreeMap<String, String> {
+        let mut values: BTreeMap<String, String> = self.fields.clone();
+        values.insert(String::from("email"), self.email.clone());
+        values.insert(String::from("name"), self.name.clone().unwrap_or_default());
+        values.insert(String::from("token"), self.token.clone());
+        values.insert(String::from("locale"), self.locale.clone().unwrap_or_default());
+        values.insert(String::from("tags"), self.tags.join(", "));
+        values
+    }
+}
+
+/// Narrows down which subscribers are listed or exported, every filter that's set
+/// has to match.
+#[derive(Debug, Clone, Default)]
+pub struct Filter {
+    pub list: Option<String>,
+    pub status: Option<String>,
+    pub tags: Vec<String>,
+    pub since: Option<i64>,
+}
+
+impl Filter {
+    pub fn new(list: Option<String>, status: Option<String>, tags: Vec<String>, since: Option<String>) -> Result<Filter, String> {
+        let since: Option<i64> = match since {
+            Some(since) => match parse_time(&since) {
+                Some(time) => Some(time),
+                None => return Err(format!("Invalid date {:?}, expected YYYY-MM-DD", since)),
+            },
+            None => None,
+        };
+
+        Ok(Filter { list, status, tags, since })
+    }
+
+    pub fn matches(&self, subscriber: &Subscriber) -> bool {
+        self.list.as_ref().is_none_or(|list| subscriber.lists.contains(list))
+            && self.status.as_ref().is_none_or(|status| &subscriber.status == status)
+            && self.tags.iter().all(|tag| subscriber.tags.contains(tag))
+            && self.since.is_none_or(|since| subscriber.created >= since)
+    }
+}
+
+/// Loads every subscriber along with their lists, tags and custom fields.
+pub async fn load_subscribers(pool: &MySqlPool) -> Result<Vec<Subscriber>, String> {
+    let records = match sqlx::query!(r#"
+                                     SELECT token, email, name, status, created, locale, frequency FROM mailing_list ORDER BY created, email"#)
+        .fetch_all(pool)
+        .await {
+            Ok(records) => records,
+            Err(err) => return Err(format!("Error reading subscribers: {}", err)),
+        };
+
+    let mut subscribers: Vec<Subscriber> = records.into_iter().map(|record| Subscriber {
+        token: record.token,
+        email: record.email,
+        name: record.name,
+        status: record.status,
+        created: record.created,
+        locale: record.locale,
+        frequency: record.frequency,
+        ..Default::default()
+    }).collect();
+    let index: HashMap<String, usize> = subscribers.iter()
+        .enumerate()
+        .map(|(index, subscriber)| (subscriber.token.clone(), index))
+        .collect();
+
+    match sqlx::query!(r#"
+                       SELECT token, list FROM list_members ORDER BY list"#)
+        .fetch_all(pool)
+        .await {
+            Ok(records) => for record in records {
+                if let Some(&index) = index.get(&record.token) {
+                    subscribers[index].lists.push(record.list);
+                }
+            },
+            Err(err) => return Err(format!("Error reading list members: {}", err)),
+        }
+
+    match sqlx::query!(r#"
+                       SELECT token, tag FROM subscriber_tags ORDER BY tag"#)
+        .fetch_all(pool)
+        .await {
+            Ok(records) => for record in records {
+                if let Some(&index) = index.get(&record.token) {
+                    subscribers[index].tags.push(record.tag);
+                }
+            },
+            Err(err) => return Err(format!("Error reading tags: {}", err)),
+        }
+
+    match sqlx::query!(r#"
+                       SELECT token, name, value FROM subscriber_fields"#)
+        .fetch_all(pool)
+        .await {
+            Ok(records) => for record in records {
+                if let Some(&index) = index.get(&record.token) {
+                    subscribers[index].fields.insert(record.name, record.value);
+                }
+            },
+            Err(err) => return Err(format!("Error reading custom fields: {}", err)),
+        }
+
+    Ok(subscribers)
+}
+
+/// Loads the subscriber with this email along with their lists, tags and custom fields.
+pub async fn load_subscriber(pool: &MySqlPool, email: &str) -> Result<Option<Subscriber>, String> {
+    let mut subscriber: Subscriber = match sqlx::query!(r#"
+                                                        SELECT token, email, name, status, created, locale, frequency FROM mailing_list WHERE email = (?)"#,
+                                                        email)
+        .fetch_optional(pool)
+        .await {
+            Ok(Some(record)) => Subscriber {
+                token: record.token,
+                email: record.email,
+                name: record.name,
+                status: record.status,
+                created: record.created,
+                locale: record.locale,
+                frequency: record.frequency,
+                ..Default::default()
+            },
+            Ok(None) => return Ok(None),
+            Err(err) => return Err(format!("Error reading subscriber: {}", err)),
+        };
+
+    match sqlx::query!(r#"
+                       SELECT list FROM list_members WHERE token = (?) ORDER BY list"#,
+                       subscriber.token)
+        .fetch_all(pool)
+        .await {
+            Ok(records) => subscriber.lists = records.into_iter().map(|record| record.list).collect(),
+            Err(err) => return Err(format!("Error reading list members: {}", err)),
+        }
+
+    match sqlx::query!(r#"
+                       SELECT tag FROM subscriber_tags WHERE token = (?) ORDER BY tag"#,
+                       subscriber.token)
+        .fetch_all(pool)
+        .await {
+            Ok(records) => subscriber.tags = records.into_iter().map(|record| record.tag).collect(),
+            Err(err) => return Err(format!("Error reading tags: {}", err)),
+        }
+
+    match sqlx::query!(r#"
+                       SELECT name, value FROM subscriber_fields WHERE token = (?)"#,
+                       subscriber.token)
+        .fetch_all(pool)
+        .await {
+            Ok(records) => subscriber.fields = records.into_iter().map(|record| (record.name, record.value)).collect(),
+            Err(err) => return Err(format!("Error reading custom fields: {}", err)),
+        }
+
+    Ok(Some(subscriber))
+}
+
+fn format_time(time: i64) -> String {
+    match Utc.timestamp_opt(time, 0).single() {
+        Some(time) if time.timestamp() > 0 => time.to_rfc3339(),
+        _ => String::new(),
+    }
+}
+
+/// Writes subscribers as CSV with the same columns -i reads, plus token, status,
+/// created, locale and frequency, and a column for every custom field any subscriber has.
+pub fn to_csv(subscribers: &[Subscriber]) -> Result<String, String> {
+    let fields: BTreeSet<&String> = subscribers.iter()
+        .flat_map(|subscriber| subscriber.fields.keys())
+        .collect();
+
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    let mut header: Vec<&str> = vec!["token", "email", "name", "status", "created", "locale", "frequency", "lists", "tags"];
+    header.extend(fields.iter().map(|field| field.as_str()));
+    if let Err(err) = writer.write_record(&header) {
+        return Err(format!("Error writing CSV: {}", err));
+    }
+
+    for subscriber in subscribers {
+        let mut row: Vec<String> = vec![
+            subscriber.token.clone(),
+            subscriber.email.clone(),
+            subscriber.name.clone().unwrap_or_default(),
+            subscriber.status.clone(),
+            format_time(subscriber.created),
+            subscriber.locale.clone().unwrap_or_default(),
+            subscriber.frequency.clone(),
+            subscriber.lists.join(";"),
+            subscriber.tags.join(";"),
+        ];
+        row.extend(fields.iter().map(|field| subscriber.fields.get(*field).cloned().unwrap_or_default()));
+        if let Err(err) = writer.write_record(&row) {
+            return Err(format!("Error writing CSV: {}", err));
+        }
+    }
+
+    match writer.into_inner() {
+        Ok(bytes) => String::from_utf8(bytes).map_err(|err| format!("Error writing CSV: {}", err)),
+        Err(err) => Err(format!("Error writing CSV: {}", err)),
+    }
+}
+
+/// Loads every subscriber matching the filter.
+pub async fn filtered(filter: &Filter) -> Result<Vec<Subscriber>, String> {
+    let config: Config = Config::load_config().unwrap();
+
+    let pool = MySqlPoolOptions::new()
+        .max_connections(5)
+        .connect(&config.url)
+        .await
+        .expect("Cannot connect to database!");
+
+    debug!("loading subscribers matching {:?}", filter);
+    let mut subscribers: Vec<Subscriber> = load_subscribers(&pool).await?;
+    subscribers.retain(|subscriber| filter.matches(subscriber));
+    Ok(subscribers)
+}
+
+/// How many subscribers are on a list.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct ListSize {
+    pub list: String,
+    pub members: i64,
+}
+
+pub async fn list_sizes(pool: &MySqlPool) -> Result<Vec<ListSize>, String> {
+    match sqlx::query!(r#"
+                       SELECT list, COUNT(*) AS members FROM list_members GROUP BY list ORDER BY list"#)
+        .fetch_all(pool)
+        .await {
+            Ok(records) => Ok(records.into_iter().map(|record| ListSize { list: record.list, members: record.members }).collect()),
+            Err(err) => Err(format!("Error reading lists: {}", err)),
+        }
+}
+
+pub async fn list_subscribers(filter: Filter) -> Result<String, String> {
+    let subscribers: Vec<Subscriber> = filtered(&filter).await?;
+
+    let mut output: String = String::new();
+    for subscriber in &subscribers {
+        output.push_str(&format!("{}\t{}\t{}\t{}\t{}\t{}\n",
+                                 subscriber.email,
+                                 subscriber.name.as_deref().unwrap_or("-"),
+                                 subscriber.status,
+                                 format_time(subscriber.created),
+                                 subscriber.lists.join(","),
+                                 subscriber.tags.join(",")));
+    }
+    output.push_str(&format!("{} subscribers", subscribers.len()));
+
+    Ok(output)
+}
+
+/// Exports subscribers to a .csv or .json file. Their token, status, signup time, locale
+/// and frequency are included for reference, importing the file ignores them and gives
+/// every subscriber a new token.
+pub async fn export_subscribers(path: String, filter: Filter) -> Result<String, String> {
+    let subscribers: Vec<Subscriber> = filtered(&filter).await?;
+
+    let contents: String = if path.to_lowercase().ends_with(".json") {
+        match serde_json::to_string_pretty(&subscribers) {
+            Ok(contents) => contents,
+            Err(err) => return Err(format!("Error writing JSON: {}", err)),
+        }
+    } else {
+        to_csv(&subscribers)?
+    };
+
+    match std::fs::write(&path, contents) {
+        Ok(_) => Ok(format!("Exported {} subscribers to {}", subscribers.len(), path)),
+        Err(err) => Err(format!("Cannot write {}: {}", path, err)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::import::{parse_csv, parse_json};
+
+    fn subscriber() -> Subscriber {
+        Subscriber {
+            token: String::from("token"),
+            email: String::from("example@test.com"),
+            name: Some(String::from("Example")),
+            status: String::from("subscribed"),
+            created: 1767225600,
+            locale: Some(String::from("de")),
+            frequency: String::from("all"),
+            lists: vec![String::from("monthly"), String::from("weekly")],
+            tags: vec![String::from("beta")],
+            fields: BTreeMap::from([(String::from("country"), String::from("DE"))]),
+        }
+    }
+
+    #[test]
+    fn filter_subscribers() {
+        let subscriber = subscriber();
+
+        assert!(Filter::default().matches(&subscriber));
+        assert!(Filter::new(Some(String::from("weekly")), None, vec![String::from("beta")], Some(String::from("2026-01-01"))).unwrap().matches(&subscriber));
+        assert!(!Filter::new(None, None, Vec::new(), Some(String::from("2026-01-02"))).unwrap().matches(&subscriber));
+        assert!(!Filter::new(None, Some(String::from("bounced")), Vec::new(), None).unwrap().matches(&subscriber));
+        assert!(Filter::new(None, None, Vec::new(), Some(String::from("yesterday"))).is_err());
+    }
+
+    #[test]
+    fn csv_export_can_be_imported() {
+        let csv = to_csv(&[subscriber()]).unwrap();
+
+        assert_eq!("token,email,name,status,created,locale,frequency,lists,tags,country\n\
+                    token,example@test.com,Example,subscribed,2026-01-01T00:00:00+00:00,de,all,monthly;weekly,beta,DE\n", csv);
+        let imported = parse_csv(&csv).unwrap().remove(0).unwrap();
+        assert_eq!(subscriber().lists, imported.lists);
+        assert_eq!(subscriber().fields, imported.fields);
+    }
+
+    #[test]
+    fn json_export_can_be_imported() {
+        let json = serde_json::to_string(&[subscriber()]).unwrap();
+
+        let imported = parse_json(&json).unwrap().remove(0).unwrap();
+        assert_eq!(subscriber().lists, imported.lists);
+        assert_eq!(subscriber().fields, imported.fields);
+    }
+}
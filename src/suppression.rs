@@ -0,0 +1,161 @@
+use sha2::{Digest, Sha256};
+use sqlx::mysql::{MySqlConnection, MySqlPool, MySqlPoolOptions};
+use chrono::Utc;
+use log::debug;
+
+use crate::Config;
+use crate::emails::{email_key, normalise_email};
+
+pub const UNSUBSCRIBED: &str = "unsubscribed";
+pub const BOUNCED: &str = "bounced";
+pub const COMPLAINED: &str = "complained";
+pub const BLOCKED: &str = "blocked";
+pub const SUBSCRIBED: &str = "subscribed";
+pub const ERASED: &str = "erased";
+
+/// Suppressions are keyed by the SHA-256 of the case-folded email, the same as MySQL's
+/// `SHA2(LOWER(email), 256)`, so entries can outlive the address itself.
+pub fn hash_email(email: &str) -> String {
+    format!("{:x}", Sha256::digest(email_key(email).as_bytes()))
+}
+
+/// Returns the reason an email is suppressed, if it is.
+pub async fn is_suppressed(connection: &mut MySqlConnection, email: &str) -> Result<Option<String>, String> {
+    match sqlx::query!(r#"
+                       SELECT reason FROM suppressions WHERE hash = (?)"#,
+                       hash_email(email))
+        .fetch_optional(connection)
+        .await {
+            Ok(record) => Ok(record.map(|record| record.reason)),
+            Err(err) => Err(format!("Error checking suppression list: {}", err)),
+        }
+}
+
+/// Sets the status of the subscriber with this email, if there is one.
+async fn set_status(pool: &MySqlPool, email: &str, status: &str) -> Result<(), String> {
+    match sqlx::query!(r#"
+                       UPDATE mailing_list SET status = (?) WHERE LOWER(email) = (?)"#,
+                       status,
+                       email_key(email))
+        .execute(pool)
+        .await {
+            Ok(_) => Ok(()),
+            Err(err) => Err(format!("Error updating the status of {}: {}", email, err)),
+        }
+}
+
+/// Adds an email to the suppression list and marks the subscriber with the reason.
+pub async fn suppress(pool: &MySqlPool, email: &str, reason: &str) -> Result<(), String> {
+    debug!("suppressing {} as {}", email, reason);
+    if let Err(err) = sqlx::query!(r#"
+                                   INSERT INTO suppressions (hash, email, reason, time) VALUES (?, ?, ?, ?)
+                                   ON DUPLICATE KEY UPDATE reason = VALUES(reason), time = VALUES(time)"#,
+                                   hash_email(email),
+                                   email,
+                                   reason,
+                                   Utc::now().timestamp())
+        .execute(pool)
+        .await {
+            return Err(format!("Error adding {} to the suppression list: {}", email, err));
+        }
+
+    set_status(pool, email, reason).await
+}
+
+/// Keeps only the hash of an erased email on the suppression list, any existing reason
+/// is kept so it still can't be re-added.
+pub async fn forget(connection: &mut MySqlConnection, email: &str) -> Result<(), sqlx::Error> {
+    debug!("forgetting {}", email);
+    sqlx::query!(r#"
+                 INSERT INTO suppressions (hash, email, reason, time) VALUES (?, NULL, ?, ?)
+                 ON DUPLICATE KEY UPDATE email = NULL"#,
+                 hash_email(email),
+                 ERASED,
+                 Utc::now().timestamp())
+        .execute(connection)
+        .await?;
+    Ok(())
+}
+
+/// Takes an email off the suppression list if it's there for `reason`, so suppressions
+/// for anything else stay in place.
+pub async fn lift(pool: &MySqlPool, email: &str, reason: &str) -> Result<(), String> {
+    debug!("lifting the {} suppression of {}", reason, email);
+    match sqlx::query!(r#"
+                       DELETE FROM suppressions WHERE hash = (?) AND reason = (?)"#,
+                       hash_email(email),
+                       reason)
+        .execute(pool)
+        .await {
+            Ok(_) => Ok(()),
+            Err(err) => Err(format!("Error removing {} from the suppression list: {}", email, err)),
+        }
+}
+
+/// Marks a notice as sent to a suppressed email, unless one was sent in the last
+/// `interval` seconds, so signing an address up over and over doesn't flood it.
+/// Returns whether the notice should be sent.
+pub async fn claim_notice(pool: &MySqlPool, email: &str, reason: &str, interval: i64) -> Result<bool, String> {
+    let now: i64 = Utc::now().timestamp();
+    match sqlx::query!(r#"
+                       UPDATE suppressions SET notified = (?) WHERE hash = (?) AND reason = (?) AND (notified IS NULL OR notified <= (?))"#,
+                       now,
+                       hash_email(email),
+                       reason,
+                       now - interval)
+        .execute(pool)
+        .await {
+            Ok(result) => Ok(result.rows_affected() > 0),
+            Err(err) => Err(format!("Error updating the suppression list: {}", err)),
+        }
+}
+
+pub async fn block_email(email: String) -> Result<String, String> {
+    let config: Config = Config::load_config().unwrap();
+    let email: String = normalise_email(&email)?;
+
+    let pool = MySqlPoolOptions::new()
+        .max_connections(5)
+        .connect(&config.url)
+        .await
+        .expect("Cannot connect to database!");
+
+    suppress(&pool, &email, BLOCKED).await?;
+    Ok(format!("Blocked {}", email))
+}
+
+pub async fn unblock_email(email: String) -> Result<String, String> {
+    let config: Config = Config::load_config().unwrap();
+    let email: String = normalise_email(&email)?;
+
+    let pool = MySqlPoolOptions::new()
+        .max_connections(5)
+        .connect(&config.url)
+        .await
+        .expect("Cannot connect to database!");
+
+    match sqlx::query!(r#"
+                       DELETE FROM suppressions WHERE hash = (?)"#,
+                       hash_email(&email))
+        .execute(&pool)
+        .await {
+            Ok(result) if result.rows_affected() > 0 => {
+                set_status(&pool, &email, SUBSCRIBED).await?;
+                Ok(format!("Removed {} from the suppression list", email))
+            },
+            Ok(_) => Err(format!("The email {} isn't on the suppression list", email)),
+            Err(err) => Err(format!("Error removing {} from the suppression list: {}", email, err)),
+        }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::hash_email;
+
+    #[test]
+    fn hash_matches_mysql_sha2() {
+        // SELECT SHA2('example@test.com', 256)
+        assert_eq!("273f6ec2fc79031c824daff15d9415db2e8f2dd2a934b6b8b13540b5f94062b0", hash_email("example@test.com"));
+        assert_eq!(hash_email("example@test.com"), hash_email("Example@Test.com"));
+    }
+}
@@ -0,0 +1,155 @@
+use serde_json::json;
+
+use crate::Config;
+use crate::preferences;
+
+/// Turns every element with a `data-newsman-signup` attribute into a signup form. The
+/// element's `data-list`, `data-button` and `data-name` attributes pick the list signed
+/// up to, the button text and whether to ask for a name.
+const SCRIPT: &str = r#"(function () {
+    "use strict";
+    var settings = {{ settings }};
+    var encoder = new TextEncoder();
+
+    function leadingZeros(hash) {
+        var bits = 0;
+        for (var i = 0; i < hash.length; i++) {
+            if (hash[i] === 0) {
+                bits += 8;
+                continue;
+            }
+            return bits + Math.clz32(hash[i]) - 24;
+        }
+        return bits;
+    }
+
+    async function solve(challenge, difficulty) {
+        for (var nonce = 0; ; nonce++) {
+            var hash = await crypto.subtle.digest("SHA-256", encoder.encode(challenge + nonce));
+            if (leadingZeros(new Uint8Array(hash)) >= difficulty) {
+                return String(nonce);
+            }
+        }
+    }
+
+    function field(form, type, name, label) {
+        var input = document.createElement("input");
+        input.type = type;
+        input.name = name;
+        if (label) {
+            var wrapper = document.createElement("label");
+            wrapper.textContent = label + " ";
+            wrapper.appendChild(input);
+            form.appendChild(wrapper);
+        } else {
+            form.appendChild(input);
+        }
+        return input;
+    }
+
+    function render(container) {
+        var form = document.createElement("form");
+        form.className = "newsman-signup";
+        var email = field(form, "email", "email", "Email");
+        email.required = true;
+        if (container.dataset.name === "true") {
+            field(form, "text", "name", "Name");
+        }
+        if (container.dataset.list) {
+            field(form, "hidden", "list").value = container.dataset.list;
+        }
+        if (settings.honeypot) {
+            var honeypot = field(form, "text", settings.honeypot);
+            honeypot.tabIndex = -1;
+            honeypot.autocomplete = "off";
+            honeypot.style.display = "none";
+        }
+        var token = settings.formToken ? field(form, "hidden", "form_token") : null;
+        var button = document.createElement("button");
+        button.type = "submit";
+        button.textContent = container.dataset.button || "Subscribe";
+        form.appendChild(button);
+        var message = document.createElement("p");
+        message.className = "newsman-message";
+        message.setAttribute("role", "status");
+        form.appendChild(message);
+        container.appendChild(form);
+
+        if (token) {
+            fetch(settings.endpoint + "api/form-token")
+                .then(function (response) { return response.json(); })
+                .then(function (reply) { token.value = reply.token; });
+        }
+
+        form.addEventListener("submit", async function (event) {
+            event.preventDefault();
+            button.disabled = true;
+            message.textContent = "";
+            try {
+                var body = new URLSearchParams(new FormData(form));
+                if (settings.difficulty > 0) {
+                    var challenge = await (await fetch(settings.endpoint + "api/challenge")).json();
+                    body.set("pow_challenge", challenge.challenge);
+                    body.set("pow_nonce", await solve(challenge.challenge, challenge.difficulty));
+                }
+                var response = await fetch(settings.endpoint + "api/add", {
+                    method: "POST",
+                    headers: { "Accept": "application/json" },
+                    body: body
+                });
+                var reply = await response.json();
+                form.dataset.result = reply.result;
+                message.textContent = reply.message;
+                if (response.ok) {
+                    form.reset();
+                }
+            } catch (err) {
+                form.dataset.result = "error";
+                message.textContent = "Something went wrong, please try again later.";
+            }
+            button.disabled = false;
+        });
+    }
+
+    document.querySelectorAll("[data-newsman-signup]").forEach(render);
+})();
+"#;
+
+/// The signup widget script, set up for the spam protection turned on in the config.
+pub fn script(config: &Config) -> String {
+    let settings = json!({
+        "endpoint": config.api_endpoint,
+        "honeypot": config.honeypot_field,
+        "formToken": config.min_fill_time > 0,
+        "difficulty": config.pow_difficulty,
+    });
+    // the settings are inside a script, so they mustn't be able to close it
+    SCRIPT.replace("{{ settings }}", &settings.to_string().replace("</", "<\\/"))
+}
+
+/// A page with just the signup widget, for embedding in an iframe.
+pub fn page() -> String {
+    preferences::page("Subscribe", "<div data-newsman-signup></div>\n<script src=\"signup.js\"></script>")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn script_settings() {
+        let config = Config {
+            api_endpoint: String::from("https://news.example.com/"),
+            honeypot_field: Some(String::from("</script>")),
+            min_fill_time: 3,
+            ..Default::default()
+        };
+        let script = script(&config);
+
+        assert!(script.contains(r#""endpoint":"https://news.example.com/""#));
+        assert!(script.contains(r#""formToken":true"#));
+        assert!(script.contains(r#""difficulty":0"#));
+        assert!(!script.contains("{{ settings }}"));
+        assert!(!script.contains("</script>"));
+    }
+}